@@ -0,0 +1,1004 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::{fs, path};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use rust_tdlib::client::auth_handler::ClientAuthStateHandler;
+use rust_tdlib::client::tdlib_client::TdJson;
+use rust_tdlib::client::{AuthStateHandlerProxy, ClientIdentifier, ClientState};
+use rust_tdlib::types::{
+    AuthorizationState, AuthorizationStateWaitCode, AuthorizationStateWaitPassword,
+    AuthorizationStateWaitPhoneNumber, AuthorizationStateWaitRegistration, LogOut, Message, RObject,
+};
+use rust_tdlib::{
+    client::{Client, Worker},
+    types::{SetTdlibParameters, Update},
+};
+use serde::Serialize;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::commands::{handle_control_command, parse_control_command};
+use crate::config::{AccountSettings, Config};
+use crate::dry_run::DryRunReport;
+use crate::entry::{attachment_info, get_message_meta, get_username, make_entry, MessageMeta};
+use crate::render::content_type_name;
+use crate::error::AppError;
+use crate::index::SearchIndex;
+use crate::logging::{mask_phone, LogContext};
+use crate::metrics::metrics;
+use crate::raw::RawStore;
+use crate::sink::MarkdownSink;
+use crate::skip_list::SkipList;
+use crate::telegram::record::{read_fixture, FixtureRecord, Recorder, RecordingApi, ReplayApi};
+use crate::telegram::cache::{CachedApi, MetadataCache};
+use crate::telegram::metered::MeteredApi;
+use crate::telegram::rate_limit::RateLimitedApi;
+use crate::telegram::{TdlibApi, TelegramApi};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Running accounts by TDLib client id, with states of all configured accounts by phone.
+///
+/// This is an index for lookups, not the owner of accounts: the updates reader routes updates by client id and
+/// status endpoints read states here. In `run` the [`crate::registry::AccountRegistry`] decides which accounts exist,
+/// their supervisors insert clients once authorized and remove them when stopped; `init` and `sync` have no registry
+/// and insert their clients directly.
+#[derive(Debug, Default)]
+pub struct Accounts {
+    clients: RwLock<HashMap<i32, Arc<ClientWithMeta>>>,
+    states: RwLock<HashMap<String, AccountState>>,
+}
+
+impl Accounts {
+    pub fn get(&self, client_id: i32) -> Option<Arc<ClientWithMeta>> {
+        self.clients.read().expect("accounts lock poisoned").get(&client_id).cloned()
+    }
+
+    pub fn insert(&self, client: Arc<ClientWithMeta>) {
+        self.clients.write().expect("accounts lock poisoned").insert(client.client_id, client);
+    }
+
+    pub fn by_phone(&self, phone: &str) -> Option<Arc<ClientWithMeta>> {
+        self.clients
+            .read()
+            .expect("accounts lock poisoned")
+            .values()
+            .find(|client| client.phone == phone)
+            .cloned()
+    }
+
+    pub fn all(&self) -> Vec<Arc<ClientWithMeta>> {
+        self.clients.read().expect("accounts lock poisoned").values().cloned().collect()
+    }
+
+    pub fn remove(&self, client_id: i32) -> Option<Arc<ClientWithMeta>> {
+        self.clients.write().expect("accounts lock poisoned").remove(&client_id)
+    }
+
+    pub fn set_state(&self, phone: &str, state: AccountState) {
+        self.states.write().expect("accounts lock poisoned").insert(phone.to_string(), state);
+    }
+
+    pub fn state(&self, phone: &str) -> Option<AccountState> {
+        self.states.read().expect("accounts lock poisoned").get(phone).cloned()
+    }
+
+    pub fn remove_state(&self, phone: &str) {
+        self.states.write().expect("accounts lock poisoned").remove(phone);
+    }
+
+    /// States of all accounts, sorted by phone.
+    pub fn states(&self) -> Vec<(String, AccountState)> {
+        let mut states = self
+            .states
+            .read()
+            .expect("accounts lock poisoned")
+            .iter()
+            .map(|(phone, state)| (phone.clone(), state.clone()))
+            .collect::<Vec<_>>();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+}
+
+/// State of a supervised account.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum AccountState {
+    Starting,
+    Running,
+    /// Authorization code is needed, the account is retried once it is submitted
+    WaitCode,
+    /// Two-step verification password is needed, the account is retried once it is submitted
+    WaitPassword,
+    /// Setup failed, it is retried after a backoff
+    Restarting { attempt: u32, error: String },
+}
+
+/// Controls of a supervised account kept across client restarts: auth input submitted through the admin API
+/// and whether capture is paused.
+#[derive(Debug, Default)]
+pub struct AccountControl {
+    code: RwLock<Option<String>>,
+    password: RwLock<Option<String>>,
+    /// Notified when a code or a password is submitted
+    submitted: Notify,
+    /// New messages are not archived while set, control commands are still handled
+    pub paused: AtomicBool,
+}
+
+impl AccountControl {
+    pub fn submit_code(&self, code: String) {
+        *self.code.write().expect("control lock poisoned") = Some(code);
+        self.submitted.notify_one();
+    }
+
+    pub fn submit_password(&self, password: String) {
+        *self.password.write().expect("control lock poisoned") = Some(password);
+        self.submitted.notify_one();
+    }
+
+    /// A code can be used once.
+    fn take_code(&self) -> Option<String> {
+        self.code.write().expect("control lock poisoned").take()
+    }
+
+    fn password(&self) -> Option<String> {
+        self.password.read().expect("control lock poisoned").clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientWithMeta {
+    pub client_id: i32,
+    pub chat_id: i64,
+    pub client: Box<dyn TelegramApi>,
+    pub phone: String,
+    pub markdown: MarkdownSink,
+    pub index: SearchIndex,
+    pub raw_store: Option<RawStore>,
+    pub source_format: SourceFormat,
+    pub processed_messages: AtomicU64,
+    /// Messages that could not be archived because of an error
+    pub failed_messages: AtomicU64,
+    /// Messages excluded with `/skip`
+    pub skipped_messages: SkipList,
+    /// Notified when TDLib closes the client, so its supervisor can restart it
+    pub closed: Notify,
+    /// Background tasks of the account, e.g. syncs started with `/sync`
+    pub tasks: Mutex<JoinSet<()>>,
+    /// Set on shutdown, running syncs stop at the next message
+    pub stopping: AtomicBool,
+    pub control: Arc<AccountControl>,
+    /// Set in a dry run: entries go to the report instead of the sinks and attachments are not downloaded
+    pub dry_run: Option<Arc<DryRunReport>>,
+    /// Chats, users and supergroups looked up by the client, updated from TDLib updates
+    pub metadata_cache: Option<Arc<MetadataCache>>,
+}
+
+impl ClientWithMeta {
+    /// Opens account's output files; `chat_id` is the id of the authorized user, i.e. of the Saved Messages chat.
+    /// In a dry run nothing is written: the data file is only read and the index is kept in memory.
+    pub fn new(
+        account: &AccountSettings,
+        client: Box<dyn TelegramApi>,
+        client_id: i32,
+        chat_id: i64,
+        dry_run: Option<Arc<DryRunReport>>,
+    ) -> Result<Self> {
+        let (markdown, index) = match dry_run {
+            None => (
+                MarkdownSink::open(&account.file_path, account.fsync)?,
+                SearchIndex::open(account.index_path()).context("open search index")?,
+            ),
+            Some(_) => (
+                MarkdownSink::open_read_only(&account.file_path)?,
+                SearchIndex::open_in_memory().context("open search index")?,
+            ),
+        };
+        let store_raw = account.store_raw && dry_run.is_none();
+
+        Ok(ClientWithMeta {
+            client_id,
+            chat_id,
+            client,
+            phone: account.phone.clone(),
+            markdown,
+            index,
+            raw_store: store_raw.then(|| RawStore::new(RawStore::default_path(&account.file_path), account.fsync)),
+            source_format: account.source_format,
+            processed_messages: AtomicU64::new(0),
+            failed_messages: AtomicU64::new(0),
+            skipped_messages: SkipList::open(SkipList::default_path(&account.file_path)).context("open skip list")?,
+            closed: Notify::new(),
+            tasks: Mutex::new(JoinSet::new()),
+            stopping: AtomicBool::new(false),
+            control: Arc::default(),
+            dry_run,
+            metadata_cache: None,
+        })
+    }
+
+    pub fn log_context(&self) -> LogContext {
+        LogContext::account(&self.phone, self.client_id, self.chat_id)
+    }
+
+    /// Waits for background tasks, stopping syncs at the next message, and flushes all sinks to disk.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.stopping.store(true, Ordering::Relaxed);
+        let mut tasks = self.tasks.lock().await;
+        while tasks.join_next().await.is_some() {}
+        self.markdown.sync().await.context("sync markdown")?;
+        if let Some(raw_store) = &self.raw_store {
+            raw_store.sync().context("sync raw store")?;
+        }
+        if let Some(cache) = self.metadata_cache.as_ref().filter(|_| self.dry_run.is_none()) {
+            cache.save().context("save metadata cache")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ClientAuthorizer {
+    phone: String,
+    auth_code: Option<String>,
+    password: Option<String>,
+    /// Only checks an existing session: the phone number is not submitted, so no auth code is sent
+    check_only: bool,
+}
+
+#[async_trait]
+impl ClientAuthStateHandler for ClientAuthorizer {
+    async fn handle_wait_code(&self, _wait_code: &AuthorizationStateWaitCode) -> String {
+        match &self.auth_code {
+            None => {
+                log::warn!("auth code is needed for {}", mask_phone(&self.phone));
+                "".to_string()
+            }
+            Some(code) => code.clone(),
+        }
+    }
+
+    async fn handle_wait_password(
+        &self,
+        _wait_password: &AuthorizationStateWaitPassword,
+    ) -> String {
+        match &self.password {
+            None => {
+                log::warn!("password is needed for {}", mask_phone(&self.phone));
+                "".to_string()
+            }
+            Some(password) => password.clone(),
+        }
+    }
+
+    async fn handle_wait_client_identifier(
+        &self,
+        _: &AuthorizationStateWaitPhoneNumber,
+    ) -> ClientIdentifier {
+        if self.check_only {
+            log::debug!("{} has no session, not requesting an auth code", mask_phone(&self.phone));
+            // TDLib rejects the empty number, which ends authorization with the phone number still awaited
+            return ClientIdentifier::PhoneNumber(String::new());
+        }
+        ClientIdentifier::PhoneNumber(self.phone.clone())
+    }
+
+    async fn handle_wait_registration(
+        &self,
+        _wait_registration: &AuthorizationStateWaitRegistration,
+    ) -> (String, String) {
+        log::warn!("{} is not registered, registration is not supported", mask_phone(&self.phone));
+        // TDLib rejects the empty name, which ends authorization with the registration still awaited
+        (String::new(), String::new())
+    }
+}
+
+pub async fn auth_clients(
+    config: Config,
+    accounts: &Accounts,
+    worker: &mut Worker<AuthStateHandlerProxy, TdJson>,
+    sender: Sender<Box<Update>>,
+    codes: HashMap<String, String>,
+    recorder: Option<Arc<Recorder>>,
+) -> Result<()> {
+    for account in config.accounts.iter() {
+        let code = codes.get(&account.phone);
+        let client_data = setup_client(
+            worker,
+            account,
+            config.telegram.api_id,
+            config.telegram.api_hash.clone(),
+            Some(sender.clone()),
+            code,
+            account.password.as_ref(),
+            recorder.clone(),
+            None,
+        )
+        .await.context(format!("setup client {}", mask_phone(&account.phone)))?;
+
+        accounts.set_state(&account.phone, AccountState::Running);
+        accounts.insert(Arc::new(client_data));
+    }
+    Ok(())
+}
+
+/// Keeps the account running: sets it up, retrying with exponential backoff, and restarts it once TDLib closes the client.
+/// If an auth code or a password is needed, waits until it is submitted to `control`.
+/// Once `stop` is set, flushes the account's output and closes its client.
+pub async fn supervise_account(
+    worker: Arc<Mutex<Worker<AuthStateHandlerProxy, TdJson>>>,
+    account: AccountSettings,
+    api_id: i32,
+    api_hash: String,
+    sender: Sender<Box<Update>>,
+    accounts: Arc<Accounts>,
+    recorder: Option<Arc<Recorder>>,
+    dry_run: Option<Arc<DryRunReport>>,
+    control: Arc<AccountControl>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        accounts.set_state(&account.phone, AccountState::Starting);
+        let code = control.take_code();
+        let password = control.password().or_else(|| account.password.clone());
+        let result = {
+            // binding needs exclusive access to the worker, so accounts are authorized one at a time
+            let mut worker = worker.lock().await;
+            setup_client(
+                &mut worker,
+                &account,
+                api_id,
+                api_hash.clone(),
+                Some(sender.clone()),
+                code.as_ref(),
+                password.as_ref(),
+                recorder.clone(),
+                dry_run.clone(),
+            )
+            .await
+        };
+        match result {
+            Ok(mut client) => {
+                client.control = control.clone();
+                let client = Arc::new(client);
+                accounts.insert(client.clone());
+                accounts.set_state(&account.phone, AccountState::Running);
+                log::info!("{} is running", mask_phone(&account.phone));
+                backoff = INITIAL_BACKOFF;
+                attempt = 0;
+
+                tokio::select! {
+                    _ = client.closed.notified() => {}
+                    Ok(_) = stop.wait_for(|stop| *stop) => {
+                        accounts.remove(client.client_id);
+                        if let Err(err) = client.shutdown().await {
+                            log::error!("{}: cannot shut down: {:#}", mask_phone(&account.phone), err);
+                        }
+                        if let Err(err) = client.client.close().await {
+                            log::error!("{}: cannot close client: {:#}", mask_phone(&account.phone), err);
+                        }
+                        log::info!("{} stopped", mask_phone(&account.phone));
+                        return;
+                    }
+                }
+                accounts.remove(client.client_id);
+                log::warn!("{} client closed, restarting", mask_phone(&account.phone));
+            }
+            Err(err) => {
+                let waiting = match err.downcast_ref::<AppError>() {
+                    Some(AppError::WaitCode) => {
+                        log::error!(
+                            "{} needs an auth code, submit it through the admin API or with `teleforward init`",
+                            mask_phone(&account.phone)
+                        );
+                        Some(AccountState::WaitCode)
+                    }
+                    Some(AppError::WaitPassword) => {
+                        log::error!(
+                            "{} needs a password, submit it through the admin API or set it in the config",
+                            mask_phone(&account.phone)
+                        );
+                        Some(AccountState::WaitPassword)
+                    }
+                    _ => None,
+                };
+                if let Some(state) = waiting {
+                    accounts.set_state(&account.phone, state);
+                    tokio::select! {
+                        _ = control.submitted.notified() => {}
+                        Ok(_) = stop.wait_for(|stop| *stop) => return,
+                    }
+                    backoff = INITIAL_BACKOFF;
+                    attempt = 0;
+                    continue;
+                }
+                attempt += 1;
+                log::error!(
+                    "{} setup failed, attempt {}, retry in {:?}: {:#}",
+                    mask_phone(&account.phone),
+                    attempt,
+                    backoff,
+                    err
+                );
+                accounts.set_state(
+                    &account.phone,
+                    AccountState::Restarting {
+                        attempt,
+                        error: format!("{:#}", err),
+                    },
+                );
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            Ok(_) = stop.wait_for(|stop| *stop) => return,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+pub async fn setup_client(
+    worker: &mut Worker<AuthStateHandlerProxy, TdJson>,
+    account: &AccountSettings,
+    api_id: i32,
+    api_hash: String,
+    sender: Option<Sender<Box<Update>>>,
+    auth_code: Option<&String>,
+    password: Option<&String>,
+    recorder: Option<Arc<Recorder>>,
+    dry_run: Option<Arc<DryRunReport>>,
+) -> Result<ClientWithMeta> {
+    let authorizer = ClientAuthorizer {
+        phone: account.phone.clone(),
+        auth_code: auth_code.cloned(),
+        password: password.cloned(),
+        check_only: false,
+    };
+    let client = build_client(account, api_id, api_hash, authorizer, sender)?;
+    let client = worker.bind_client(client).await.context("bind client to worker")?;
+    wait_authorized(&client, &worker).await.context("wait authorized")?;
+    log::debug!("{} authorized", mask_phone(&account.phone));
+
+    let client_id = client.get_client_id().context("client_id not set")?;
+    // metrics are served without authentication, so accounts are labeled with masked phones
+    let label = mask_phone(&account.phone);
+    let mut api: Box<dyn TelegramApi> = Box::new(MeteredApi::new(Box::new(TdlibApi::new(client)), &label));
+    api = Box::new(RateLimitedApi::new(api, &label, account.requests_per_second()));
+    let ttl = account.metadata_cache_ttl();
+    let metadata_cache = (!ttl.is_zero())
+        .then(|| Arc::new(MetadataCache::new(ttl, account.metadata_cache_file.as_ref().map(path::PathBuf::from))));
+    if let Some(cache) = &metadata_cache {
+        api = Box::new(CachedApi::new(api, cache.clone()));
+    }
+    if let Some(recorder) = recorder {
+        api = Box::new(RecordingApi::new(api, client_id, recorder));
+    }
+    let me = api.get_me().await?;
+    log::debug!("authorized as: {:?}", me);
+
+    let mut client_meta = ClientWithMeta::new(account, api, client_id, me.id(), dry_run)?;
+    client_meta.metadata_cache = metadata_cache;
+    Ok(client_meta)
+}
+
+fn build_client(
+    account: &AccountSettings,
+    api_id: i32,
+    api_hash: String,
+    authorizer: ClientAuthorizer,
+    sender: Option<Sender<Box<Update>>>,
+) -> Result<Client<TdJson>> {
+    let mut builder = Client::builder()
+        .with_tdlib_parameters(
+            SetTdlibParameters::builder()
+                .database_directory(&account.tddb_dir)
+                .use_test_dc(false)
+                .api_id(api_id)
+                .api_hash(api_hash)
+                .system_language_code("en")
+                .device_model("Desktop")
+                .system_version("Unknown")
+                .application_version(env!("CARGO_PKG_VERSION"))
+                .enable_storage_optimizer(true)
+                .build(),
+        )
+        .with_auth_state_channel(10)
+        .with_client_auth_state_handler(authorizer);
+    match sender {
+        None => {}
+        Some(sender) => {
+            builder = builder.with_updates_sender(sender);
+        }
+    }
+    builder.build().context("client parameters setup")
+}
+
+/// Authorization state of an account's TDLib session.
+#[derive(Debug)]
+pub enum SessionStatus {
+    Authorized {
+        user_id: i64,
+        name: String,
+        username: Option<String>,
+    },
+    /// Not logged in, or waiting for a code or a password
+    NotAuthorized(AppError),
+}
+
+/// Binds a client to the account's database without requesting an auth code if the session is not authorized.
+/// The client must be closed afterwards, whether it is authorized or not.
+async fn bind_session(
+    worker: &mut Worker<AuthStateHandlerProxy, TdJson>,
+    account: &AccountSettings,
+    api_id: i32,
+    api_hash: String,
+) -> Result<(Client<TdJson>, Result<(), AppError>)> {
+    let authorizer = ClientAuthorizer {
+        phone: account.phone.clone(),
+        auth_code: None,
+        password: None,
+        check_only: true,
+    };
+    let client = build_client(account, api_id, api_hash, authorizer, None)?;
+    let client = worker.bind_client(client).await.context("bind client to worker")?;
+    let authorized = wait_authorized(&client, worker).await;
+    Ok((client, authorized))
+}
+
+/// Reports whether the account's session in `tddb_dir` is authorized and as whom.
+pub async fn session_status(
+    worker: &mut Worker<AuthStateHandlerProxy, TdJson>,
+    account: &AccountSettings,
+    api_id: i32,
+    api_hash: String,
+) -> Result<SessionStatus> {
+    let (client, authorized) = bind_session(worker, account, api_id, api_hash).await?;
+    read_session_status(&TdlibApi::new(client), authorized).await
+}
+
+/// Status of a bound session, `authorized` is the outcome of its authorization. Closes the client in any case.
+async fn read_session_status(api: &dyn TelegramApi, authorized: Result<(), AppError>) -> Result<SessionStatus> {
+    let status = match authorized {
+        Ok(()) => api.get_me().await.map(|me| SessionStatus::Authorized {
+            user_id: me.id(),
+            name: format!("{} {}", me.first_name(), me.last_name()).trim().to_string(),
+            username: get_username(me.usernames()),
+        }),
+        Err(err) => Ok(SessionStatus::NotAuthorized(err)),
+    };
+    let closed = api.close().await;
+    let status = status?;
+    closed?;
+    Ok(status)
+}
+
+/// Terminates the account's session, TDLib removes its data from `tddb_dir` then.
+/// Returns `false` if the session is not authorized.
+pub async fn log_out(
+    worker: &mut Worker<AuthStateHandlerProxy, TdJson>,
+    account: &AccountSettings,
+    api_id: i32,
+    api_hash: String,
+) -> Result<bool> {
+    let (client, authorized) = bind_session(worker, account, api_id, api_hash).await?;
+    if let Err(err) = authorized {
+        log::info!("{} is not authorized: {}", mask_phone(&account.phone), err);
+        TdlibApi::new(client).close().await?;
+        return Ok(false);
+    }
+    // TDLib closes the client once the session is terminated
+    if let Err(err) = client.log_out(LogOut::builder().build()).await {
+        if let Err(close_err) = TdlibApi::new(client).close().await {
+            log::error!("{}: cannot close client: {:#}", mask_phone(&account.phone), close_err);
+        }
+        return Err(err).context("telegram:log_out");
+    }
+    Ok(true)
+}
+
+/// Runs a history sync in the background, as one of the account's tasks.
+pub async fn spawn_sync(client_meta: Arc<ClientWithMeta>) {
+    let mut tasks = client_meta.tasks.lock().await;
+    let client_meta = client_meta.clone();
+    tasks.spawn(async move {
+        if let Err(err) = sync(&client_meta, &SyncOptions::default()).await {
+            log::error!("sync failed: {:?}", err);
+        }
+    });
+}
+
+/// Bounds of a history sync, the whole history is synchronized by default.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Only messages sent at or after this time (UTC)
+    pub since: Option<NaiveDateTime>,
+    /// Only messages sent before this time (UTC)
+    pub until: Option<NaiveDateTime>,
+    /// At most this many messages, newest first
+    pub limit: Option<usize>,
+    /// Only messages from this source, as written in the "From" line
+    pub source: Option<String>,
+}
+
+pub async fn sync(acc_data: &ClientWithMeta, options: &SyncOptions) -> Result<()> {
+    acc_data.log_context().scope(sync_history(acc_data, options)).await
+}
+
+async fn sync_history(acc_data: &ClientWithMeta, options: &SyncOptions) -> Result<()> {
+    // TODO: create backup
+    log::info!("start sync for client {}, {:?}", acc_data.chat_id, options);
+    let mut from_msg_id = 0;
+    acc_data
+        .client
+        .create_private_chat(acc_data.chat_id)
+        .await.context(r#"get "SavedMessages" chat"#)?;
+    let mut total_processed_messages = 0;
+    loop {
+        let messages = acc_data
+            .client
+            .get_chat_history(acc_data.chat_id, from_msg_id, 100)
+            .await?;
+        if messages.is_empty() {
+            log::debug!("processed {} messages", total_processed_messages);
+            return Ok(());
+        }
+
+        for msg in messages.iter() {
+            if acc_data.stopping.load(Ordering::Relaxed) {
+                log::info!("sync stopped at message {}, processed {} messages", msg.id(), total_processed_messages);
+                return Ok(());
+            }
+            from_msg_id = msg.id();
+            let date = NaiveDateTime::from_timestamp_opt(msg.date() as i64, 0).context("cannot parse message date")?;
+            // history goes from newest to oldest
+            if options.until.is_some_and(|until| date >= until) {
+                continue;
+            }
+            if options.since.is_some_and(|since| date < since) {
+                log::info!("reached --since, processed {} messages", total_processed_messages);
+                return Ok(());
+            }
+            // a repeated sync only archives what is missing
+            if acc_data.markdown.contains(msg.id()) || acc_data.skipped_messages.contains(msg.id()) {
+                continue;
+            }
+            if options.limit.is_some_and(|limit| total_processed_messages >= limit) {
+                log::info!("reached --limit, processed {} messages", total_processed_messages);
+                return Ok(());
+            }
+            // resolved once, the entry reuses it
+            let message_meta = match &options.source {
+                Some(source) => match get_message_meta(msg, acc_data).await {
+                    Ok(meta) if meta.channel_name.as_ref() == Some(source) => Some(meta),
+                    Ok(_) => continue,
+                    Err(err) => {
+                        log::debug!("skip message {}, source is unknown: {:#}", msg.id(), err);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            total_processed_messages += 1;
+            let context = acc_data.log_context().with_message(msg.id());
+            context
+                .scope(async {
+                    if let Err(err) = process_message_with_meta(msg, acc_data, message_meta).await {
+                        acc_data.failed_messages.fetch_add(1, Ordering::Relaxed);
+                        log::error!("cannot process message: {:#}", err);
+                    }
+                })
+                .await;
+        }
+        log::info!("processed {} messages", total_processed_messages,);
+    }
+}
+
+async fn wait_authorized(
+    client: &Client<TdJson>,
+    worker: &Worker<AuthStateHandlerProxy, TdJson>,
+) -> Result<(), AppError> {
+    loop {
+        match worker.wait_auth_state_change(&client).await.context("worker:wait_auth_state_change") {
+            Ok(res) => match res {
+                Ok(state) => match state {
+                    ClientState::Opened => {
+                        log::debug!("client authorized; can start interaction");
+                        break;
+                    }
+                    _ => {
+                        return Err(AppError::NotAuthorized(format!("{:?}", state)));
+                    }
+                },
+                Err((err, auth_state)) => {
+                    return match auth_state.authorization_state() {
+                        AuthorizationState::WaitCode(_) => Err(AppError::WaitCode)?,
+                        AuthorizationState::WaitPassword(_) => Err(AppError::WaitPassword)?,
+                        AuthorizationState::WaitRegistration(_) => Err(AppError::RegistrationRequired)?,
+                        AuthorizationState::WaitPhoneNumber(_) => Err(AppError::NotLoggedIn(err.to_string()))?,
+                        _ => Err(AppError::TdlibError(err))?,
+                    }
+                }
+            },
+            Err(err) => {
+                return Err(AppError::AuthStateUnavailable(format!("{:#}", err)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Dispatches new messages to their accounts until the updates channel is closed.
+/// Errors are logged and counted per account, so one failing message doesn't stop others.
+/// On shutdown the channel is closed for new updates and already queued ones are processed.
+pub fn create_updates_reader(
+    mut receiver: Receiver<Box<Update>>,
+    accounts: Arc<Accounts>,
+    recorder: Option<Arc<Recorder>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let _reader_up = ReaderUp::new();
+        loop {
+            metrics().updates_queue_depth.set(receiver.len() as i64);
+            let message = tokio::select! {
+                message = receiver.recv() => message,
+                Ok(_) = shutdown.wait_for(|stop| *stop), if !*shutdown.borrow() => {
+                    log::info!("draining {} queued updates", receiver.len());
+                    receiver.close();
+                    continue;
+                }
+            };
+            let Some(message) = message else { break };
+            match message.as_ref() {
+                Update::NewMessage(new_message) => {
+                    let client_id = new_message.client_id().unwrap_or(-1);
+                    if let Some(recorder) = &recorder {
+                        recorder.record_update(client_id, message.as_ref());
+                    }
+                    let data = match accounts.get(client_id) {
+                        None => {
+                            log::debug!("client_id not found: {}", client_id);
+                            continue;
+                        }
+                        Some(data) => data,
+                    };
+                    let context = data.log_context().with_message(new_message.message().id());
+                    context
+                        .scope(async {
+                            if let Err(err) = handle_new_message(new_message.message(), data.clone()).await {
+                                data.failed_messages.fetch_add(1, Ordering::Relaxed);
+                                log::error!("cannot handle message: {:#}", err);
+                            }
+                        })
+                        .await;
+                }
+                Update::NewChat(update) => {
+                    if let Some(cache) = metadata_cache(&accounts, update.client_id()) {
+                        cache.insert_chat(update.chat().clone());
+                    }
+                }
+                Update::ChatTitle(update) => {
+                    if let Some(cache) = metadata_cache(&accounts, update.client_id()) {
+                        cache.invalidate_chat(update.chat_id());
+                    }
+                }
+                Update::User(update) => {
+                    if let Some(cache) = metadata_cache(&accounts, update.client_id()) {
+                        cache.insert_user(update.user().clone());
+                    }
+                }
+                Update::Supergroup(update) => {
+                    if let Some(cache) = metadata_cache(&accounts, update.client_id()) {
+                        cache.insert_supergroup(update.supergroup().clone());
+                    }
+                }
+                Update::AuthorizationState(state) => {
+                    if let AuthorizationState::Closed(_) = state.authorization_state() {
+                        if let Some(data) = accounts.get(state.client_id().unwrap_or(-1)) {
+                            data.closed.notify_one();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    })
+}
+
+/// Metadata cache of the account the update is for; updates of accounts that are still starting are dropped.
+fn metadata_cache(accounts: &Accounts, client_id: Option<i32>) -> Option<Arc<MetadataCache>> {
+    accounts.get(client_id.unwrap_or(-1))?.metadata_cache.clone()
+}
+
+/// Sets `reader_up` while the reader runs and resets it once the reader stops, even by a panic.
+struct ReaderUp;
+
+impl ReaderUp {
+    fn new() -> Self {
+        metrics().reader_up.set(1);
+        Self
+    }
+}
+
+impl Drop for ReaderUp {
+    fn drop(&mut self) {
+        metrics().reader_up.set(0);
+    }
+}
+
+pub async fn handle_new_message(message: &Message, data: Arc<ClientWithMeta>) -> Result<()> {
+    log::trace!("chat_id of message: {}, expected chat_id: {}", message.chat_id(), &data.chat_id);
+    if message.chat_id() != data.chat_id {
+        return Ok(());
+    }
+    // messages sent by teleforward itself (e.g. replies to `/status`)
+    if message.sending_state().is_some() {
+        return Ok(());
+    }
+    if let Some(command) = parse_control_command(message) {
+        if data.dry_run.is_some() {
+            log::info!("dry run, control command ignored: {:?}", command);
+            return Ok(());
+        }
+        return handle_control_command(command, message, data).await.context("handle control command");
+    }
+    if data.control.paused.load(Ordering::Relaxed) {
+        log::debug!("capture paused, skip message {}", message.id());
+        return Ok(());
+    }
+    process_message(message, &data).await.context("process message")
+}
+
+/// Converts the message and writes it to all sinks of the account.
+pub async fn process_message(message: &Message, client_meta: &ClientWithMeta) -> Result<()> {
+    process_message_with_meta(message, client_meta, None).await
+}
+
+/// Same as [`process_message`], with metadata of the message if it was already resolved.
+async fn process_message_with_meta(
+    message: &Message,
+    client_meta: &ClientWithMeta,
+    message_meta: Option<MessageMeta>,
+) -> Result<()> {
+    log::trace!("message content: {:?}", message);
+    if parse_control_command(message).is_some() {
+        log::debug!("skip control command {}", message.id());
+        return Ok(());
+    }
+    if client_meta.skipped_messages.contains(message.id()) {
+        log::debug!("skip excluded message {}", message.id());
+        return Ok(());
+    }
+    if client_meta.markdown.contains(message.id()) {
+        log::debug!("skip already archived message {}", message.id());
+        return Ok(());
+    }
+    let entry = match make_entry(message, client_meta, message_meta).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            match err.downcast_ref::<AppError>() {
+                Some(AppError::UnsupportedChatType(_)) | Some(AppError::UnsupportedMessageOrigin) => {
+                    log::warn!("skip message {}: {:#}", message.id(), err)
+                }
+                _ => {
+                    client_meta.failed_messages.fetch_add(1, Ordering::Relaxed);
+                    log::error!("{:#}", err)
+                }
+            }
+            return Ok(());
+        }
+    };
+    let text = entry.render()?;
+    if let Some(report) = &client_meta.dry_run {
+        let attachment_size = attachment_info(message.content()).map(|(_, size)| size);
+        report.add(content_type_name(message.content()), &text, attachment_size)?;
+        client_meta.processed_messages.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    client_meta.markdown.append(message.id(), &text).await?;
+    client_meta.processed_messages.fetch_add(1, Ordering::Relaxed);
+    metrics()
+        .processed_messages
+        .with_label_values(&[&mask_phone(&client_meta.phone), content_type_name(message.content())])
+        .inc();
+    if let Some(raw_store) = &client_meta.raw_store {
+        if let Err(err) = raw_store.append(&entry.raw) {
+            log::error!("cannot store raw message {}: {}", message.id(), err);
+        }
+    }
+    let index_entry = entry.index_entry(&client_meta.phone, client_meta.markdown.data_dir()?);
+    if let Err(err) = client_meta.index.add(&index_entry) {
+        log::error!("cannot index message {}: {}", message.id(), err);
+    }
+    Ok(())
+}
+
+/// Feeds recorded updates through the pipeline, answering telegram calls with recorded responses.
+/// Returns paths of produced outputs, one per recorded client.
+pub async fn replay_fixture(fixture: &path::Path, output_dir: &path::Path) -> Result<Vec<path::PathBuf>> {
+    let records = read_fixture(fixture)?;
+    let client_ids = records
+        .iter()
+        .map(|r| match r {
+            FixtureRecord::Update { client_id, .. } | FixtureRecord::Call { client_id, .. } => *client_id,
+        })
+        .collect::<BTreeSet<_>>();
+
+    let mut outputs = Vec::new();
+    for client_id in client_ids {
+        let api = ReplayApi::new(&records, client_id, output_dir.join("downloads"));
+        let me = api.get_me().await.context("replay get_me")?;
+        let file_path = output_dir.join(format!("{}.md", client_id));
+        if file_path.exists() {
+            fs::remove_file(&file_path).context("remove previous output")?;
+        }
+        let account = AccountSettings {
+            phone: format!("replay-{}", client_id),
+            file_path: file_path.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let data = Arc::new(ClientWithMeta::new(&account, Box::new(api), client_id, me.id(), None)?);
+        for record in records.iter() {
+            let update = match record {
+                FixtureRecord::Update { client_id: id, update } if *id == client_id => update,
+                _ => continue,
+            };
+            let update: Update = serde_json::from_value(update.clone()).context("parse recorded update")?;
+            if let Update::NewMessage(new_message) = update {
+                let context = data.log_context().with_message(new_message.message().id());
+                context.scope(handle_new_message(new_message.message(), data.clone())).await?;
+            }
+        }
+        outputs.push(file_path);
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_tdlib::types::User;
+
+    use super::{read_session_status, SessionStatus};
+    use crate::error::AppError;
+    use crate::telegram::fake::FakeTelegram;
+
+    #[tokio::test]
+    async fn test_read_session_status() {
+        let me = User::from_json(
+            r#"{"@type":"user","id":7,"first_name":"Ann","last_name":"","usernames":{"@type":"usernames","active_usernames":["ann"],"disabled_usernames":[],"editable_username":"ann"}}"#,
+        )
+        .expect("cannot parse json");
+        let fake = FakeTelegram::new(me);
+        match read_session_status(&fake, Ok(())).await.expect("cannot read status") {
+            SessionStatus::Authorized { user_id, name, username } => {
+                assert_eq!((user_id, name.as_str(), username.as_deref()), (7, "Ann", Some("ann")));
+            }
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(fake.is_closed());
+
+        // the client of a session waiting for a code is closed too
+        let fake = FakeTelegram::default();
+        let status = read_session_status(&fake, Err(AppError::WaitCode)).await.expect("cannot read status");
+        assert!(matches!(status, SessionStatus::NotAuthorized(AppError::WaitCode)));
+        assert!(fake.is_closed());
+
+        // an authorized session whose user cannot be fetched
+        let fake = FakeTelegram::default();
+        assert!(read_session_status(&fake, Ok(())).await.is_err());
+        assert!(fake.is_closed());
+    }
+}
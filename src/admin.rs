@@ -0,0 +1,174 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::watch;
+
+use crate::account::{spawn_sync, AccountControl, AccountState};
+use crate::logging::mask_phone;
+use crate::registry::AccountRegistry;
+
+#[derive(Debug, Serialize)]
+struct AccountInfo {
+    phone: String,
+    #[serde(flatten)]
+    state: Option<AccountState>,
+    paused: bool,
+    /// Counters of the running client, reset on restart
+    processed_messages: Option<u64>,
+    failed_messages: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeRequest {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PasswordRequest {
+    password: String,
+}
+
+/// Error response with a JSON body, e.g. `{"error": "account is not running: +79991234567"}`.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+/// Serves the admin API until shutdown. With `token` set every request must have an `Authorization: Bearer <token>`
+/// header, without it the API must only be reachable locally.
+pub async fn serve(
+    listen: &str,
+    token: Option<String>,
+    registry: Arc<AccountRegistry>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut app = Router::new()
+        .route("/accounts", get(list_accounts))
+        .route("/accounts/:phone/code", post(submit_code))
+        .route("/accounts/:phone/password", post(submit_password))
+        .route("/accounts/:phone/sync", post(start_sync))
+        .route("/accounts/:phone/pause", post(pause))
+        .route("/accounts/:phone/resume", post(resume))
+        .with_state(registry);
+    if let Some(token) = token {
+        app = app.layer(middleware::from_fn_with_state(Arc::new(token), authorize));
+    }
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .context(format!("listen on {}", listen))?;
+    log::info!("admin listener on {}", listen);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        })
+        .await
+        .context("admin server")
+}
+
+async fn authorize(State(token): State<Arc<String>>, request: Request, next: Next) -> Result<Response, ApiError> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(bearer) if tokens_equal(bearer.as_bytes(), token.as_bytes()) => Ok(next.run(request).await),
+        _ => Err(ApiError(StatusCode::UNAUTHORIZED, "invalid or missing bearer token".to_string())),
+    }
+}
+
+/// Compares in time independent of where tokens differ, so the token cannot be guessed byte by byte.
+fn tokens_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn list_accounts(State(registry): State<Arc<AccountRegistry>>) -> Json<Vec<AccountInfo>> {
+    let mut accounts = Vec::new();
+    for phone in registry.phones().await {
+        let client = registry.accounts().by_phone(&phone);
+        let paused = match registry.control(&phone).await {
+            Some(control) => control.paused.load(Ordering::Relaxed),
+            None => false,
+        };
+        accounts.push(AccountInfo {
+            state: registry.accounts().state(&phone),
+            paused,
+            processed_messages: client.as_ref().map(|c| c.processed_messages.load(Ordering::Relaxed)),
+            failed_messages: client.as_ref().map(|c| c.failed_messages.load(Ordering::Relaxed)),
+            phone,
+        });
+    }
+    Json(accounts)
+}
+
+async fn control(registry: &AccountRegistry, phone: &str) -> Result<Arc<AccountControl>, ApiError> {
+    registry
+        .control(phone)
+        .await
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("account not found: {}", mask_phone(phone))))
+}
+
+async fn submit_code(
+    State(registry): State<Arc<AccountRegistry>>,
+    Path(phone): Path<String>,
+    Json(request): Json<CodeRequest>,
+) -> Result<StatusCode, ApiError> {
+    let code = request.code.trim();
+    if code.is_empty() {
+        return Err(ApiError(StatusCode::BAD_REQUEST, "code is empty".to_string()));
+    }
+    control(&registry, &phone).await?.submit_code(code.to_string());
+    log::info!("{}: auth code submitted through the admin API", mask_phone(&phone));
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn submit_password(
+    State(registry): State<Arc<AccountRegistry>>,
+    Path(phone): Path<String>,
+    Json(request): Json<PasswordRequest>,
+) -> Result<StatusCode, ApiError> {
+    if request.password.is_empty() {
+        return Err(ApiError(StatusCode::BAD_REQUEST, "password is empty".to_string()));
+    }
+    control(&registry, &phone).await?.submit_password(request.password);
+    log::info!("{}: password submitted through the admin API", mask_phone(&phone));
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Same as `/sync`: messages already archived or skipped are not archived again, so repeating it is safe.
+async fn start_sync(
+    State(registry): State<Arc<AccountRegistry>>,
+    Path(phone): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    control(&registry, &phone).await?;
+    let client = registry
+        .accounts()
+        .by_phone(&phone)
+        .ok_or_else(|| ApiError(StatusCode::CONFLICT, format!("account is not running: {}", mask_phone(&phone))))?;
+    spawn_sync(client).await;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn pause(State(registry): State<Arc<AccountRegistry>>, Path(phone): Path<String>) -> Result<StatusCode, ApiError> {
+    control(&registry, &phone).await?.paused.store(true, Ordering::Relaxed);
+    log::info!("{}: capture paused through the admin API", mask_phone(&phone));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn resume(State(registry): State<Arc<AccountRegistry>>, Path(phone): Path<String>) -> Result<StatusCode, ApiError> {
+    control(&registry, &phone).await?.paused.store(false, Ordering::Relaxed);
+    log::info!("{}: capture resumed through the admin API", mask_phone(&phone));
+    Ok(StatusCode::NO_CONTENT)
+}
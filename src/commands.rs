@@ -0,0 +1,190 @@
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rust_tdlib::types::{Message, MessageContent, MessageReplyTo, TextEntityType};
+
+use crate::account::{spawn_sync, ClientWithMeta};
+use crate::entry::make_entry;
+use crate::render::{parse_message_content, render_entry};
+
+/// Commands sent to Saved Messages to control the archiver.
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    /// `/tag foo bar` in reply to a message replaces tags of its entry.
+    Tag(Vec<String>),
+    /// `/skip` in reply to a message removes its entry and excludes it from further syncs.
+    Skip,
+    /// `/sync` runs history synchronization.
+    Sync,
+    /// `/status` replies with archiver stats.
+    Status,
+}
+
+pub fn parse_control_command(message: &Message) -> Option<ControlCommand> {
+    let text = match message.content() {
+        MessageContent::MessageText(text) => text.text(),
+        _ => return None,
+    };
+    let command_entity = text.entities().iter().find(|e| {
+        e.offset() == 0 && matches!(e.type_(), TextEntityType::BotCommand(_))
+    })?;
+    // entities offsets are measured in UTF-16 code units
+    let utf16 = text.text().encode_utf16().collect::<Vec<u16>>();
+    let length = (command_entity.length() as usize).min(utf16.len());
+    let command = String::from_utf16_lossy(&utf16[..length]);
+    let args = String::from_utf16_lossy(&utf16[length..]);
+    // strip leading slash and optional `@botname` suffix
+    let command = command.trim_start_matches('/');
+    let command = command.split('@').next().unwrap_or_default();
+    match command.to_lowercase().as_str() {
+        "tag" => Some(ControlCommand::Tag(
+            args.split_whitespace()
+                .map(|t| t.trim_start_matches('#').to_string())
+                .filter(|t| !t.is_empty())
+                .collect(),
+        )),
+        "skip" => Some(ControlCommand::Skip),
+        "sync" => Some(ControlCommand::Sync),
+        "status" => Some(ControlCommand::Status),
+        _ => None,
+    }
+}
+
+pub fn replied_message_id(message: &Message) -> Option<i64> {
+    match message.reply_to() {
+        Some(MessageReplyTo::Message(reply)) => Some(reply.message_id()),
+        _ => None,
+    }
+}
+
+/// Entry of an archived message rendered again with `tags`, from the raw store if the message is there,
+/// otherwise from the message requested again.
+async fn tagged_entry(client_meta: &ClientWithMeta, message_id: i64, tags: Vec<String>) -> Result<String> {
+    let stored = match &client_meta.raw_store {
+        Some(raw_store) => raw_store.get(message_id).context("read raw store")?,
+        None => None,
+    };
+    let (mut raw, body) = match stored {
+        Some(raw) => {
+            let body = parse_message_content(raw.message.content(), raw.attachment.as_deref())
+                .context("message has nothing to archive")?;
+            (raw, body)
+        }
+        None => {
+            let message = client_meta.client.get_message(client_meta.chat_id, message_id).await?;
+            let entry = make_entry(&message, client_meta, None)
+                .await?
+                .context("message has nothing to archive")?;
+            (entry.raw, entry.body)
+        }
+    };
+    raw.tags = tags;
+    render_entry(&raw, &body)
+}
+
+pub async fn handle_control_command(
+    command: ControlCommand,
+    message: &Message,
+    client_meta: Arc<ClientWithMeta>,
+) -> Result<()> {
+    log::info!("control command received: {:?}", command);
+    match command {
+        ControlCommand::Tag(tags) => {
+            let message_id = match replied_message_id(message) {
+                None => {
+                    log::warn!("/tag must be sent in reply to an archived message");
+                    return Ok(());
+                }
+                Some(id) => id,
+            };
+            if !client_meta.markdown.contains(message_id) {
+                log::warn!("entry for message {} not found", message_id);
+                return Ok(());
+            }
+            let entry = tagged_entry(&client_meta, message_id, tags.clone())
+                .await
+                .context(format!("render message {} with tags", message_id))?;
+            let found = client_meta.markdown.rewrite_entry(message_id, |_| Some(entry)).await?;
+            if !found {
+                log::warn!("entry for message {} not found", message_id);
+            }
+            if let Some(raw_store) = &client_meta.raw_store {
+                raw_store
+                    .update(|raw| {
+                        if raw.message.id() == message_id {
+                            raw.tags = tags.clone();
+                        }
+                        true
+                    })
+                    .context("update raw store")?;
+            }
+        }
+        ControlCommand::Skip => {
+            let message_id = match replied_message_id(message) {
+                None => {
+                    log::warn!("/skip must be sent in reply to a message");
+                    return Ok(());
+                }
+                Some(id) => id,
+            };
+            client_meta.skipped_messages.insert(message_id).context("save skip list")?;
+            client_meta.index.remove(&client_meta.phone, message_id).context("remove from index")?;
+            if let Some(raw_store) = &client_meta.raw_store {
+                raw_store.update(|raw| raw.message.id() != message_id).context("update raw store")?;
+            }
+            if !client_meta.markdown.rewrite_entry(message_id, |_| None).await? {
+                log::debug!("entry for message {} not found, only excluded", message_id);
+            }
+        }
+        ControlCommand::Sync => spawn_sync(client_meta).await,
+        ControlCommand::Status => {
+            let file_size = fs::metadata(client_meta.markdown.path()).map(|m| m.len()).unwrap_or(0);
+            let status = format!(
+                "teleforward {}\narchived messages: {}\nfailed messages: {}\nexcluded messages: {}\nfile: {} ({} bytes)",
+                env!("CARGO_PKG_VERSION"),
+                client_meta.processed_messages.load(Ordering::Relaxed),
+                client_meta.failed_messages.load(Ordering::Relaxed),
+                client_meta.skipped_messages.len(),
+                client_meta.markdown.path().display(),
+                file_size,
+            );
+            client_meta.client.send_text(client_meta.chat_id, message.id(), status).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_tdlib::types::Message;
+
+    use super::{parse_control_command, ControlCommand};
+
+    #[test]
+    fn test_parse_control_command() {
+        let tests = vec![
+            (
+                r#"{"@type":"message","id":2,"chat_id":1,"content":{"@type":"messageText","text":{"@type":"formattedText","text":"/tag foo #bar","entities":[{"@type":"textEntity","offset":0,"length":4,"type":{"@type":"textEntityTypeBotCommand"}}]}}}"#,
+                Some(ControlCommand::Tag(vec!["foo".to_string(), "bar".to_string()])),
+            ),
+            (
+                r#"{"@type":"message","id":2,"chat_id":1,"content":{"@type":"messageText","text":{"@type":"formattedText","text":"/status@teleforward","entities":[{"@type":"textEntity","offset":0,"length":19,"type":{"@type":"textEntityTypeBotCommand"}}]}}}"#,
+                Some(ControlCommand::Status),
+            ),
+            (
+                r#"{"@type":"message","id":2,"chat_id":1,"content":{"@type":"messageText","text":{"@type":"formattedText","text":"see /skip","entities":[{"@type":"textEntity","offset":4,"length":5,"type":{"@type":"textEntityTypeBotCommand"}}]}}}"#,
+                None,
+            ),
+            (
+                r#"{"@type":"message","id":2,"chat_id":1,"content":{"@type":"messageText","text":{"@type":"formattedText","text":"/unknown","entities":[{"@type":"textEntity","offset":0,"length":8,"type":{"@type":"textEntityTypeBotCommand"}}]}}}"#,
+                None,
+            ),
+        ];
+        for (json_data, expected) in tests {
+            let message = Message::from_json(json_data).expect("cannot parse json");
+            assert_eq!(parse_control_command(&message), expected);
+        }
+    }
+}
@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use serde_yaml::Value;
+
+use crate::index::default_index_path;
+use crate::logging::LogFormat;
+
+const DEFAULT_REQUESTS_PER_SECOND: f64 = 10.0;
+const DEFAULT_METADATA_CACHE_TTL: u64 = 3600;
+
+/// Unknown keys are rejected here and in nested sections, so a misspelled setting is not silently ignored.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub accounts: Vec<AccountSettings>,
+    pub telegram: TelegramConfig,
+
+    #[serde(default = "default_loglevel")]
+    pub log_level: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    /// Health and metrics endpoint, disabled if not set
+    pub http: Option<HttpConfig>,
+    /// Admin API, disabled if not set
+    pub admin: Option<AdminConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address to listen on, e.g. `0.0.0.0:9090`
+    pub listen: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address to listen on, e.g. `127.0.0.1:9091`; other than loopback ones require `token`
+    pub listen: String,
+    /// Requests must have an `Authorization: Bearer <token>` header if set
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub token: Option<String>,
+    /// Read `token` from this file, e.g. a docker secret
+    pub token_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TelegramConfig {
+    #[serde(default = "default_verbosity")]
+    pub tdlib_log_verbosity: i32,
+
+    pub api_id: i32,
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub api_hash: String,
+    /// Read `api_hash` from this file, e.g. a docker secret
+    pub api_hash_file: Option<String>,
+    /// Write TDLib log to this file instead of stderr
+    pub tdlib_log_file: Option<String>,
+}
+
+const fn default_verbosity() -> i32 {
+    1
+}
+
+fn default_loglevel() -> String {
+    "error".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AccountSettings {
+    #[serde(default)]
+    pub phone: String,
+    /// Read `phone` from this file, e.g. a docker secret
+    pub phone_file: Option<String>,
+    /// Two-step verification password, can be submitted through the admin API instead
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub password: Option<String>,
+    /// Read `password` from this file, e.g. a docker secret
+    pub password_file: Option<String>,
+    pub tddb_dir: String,
+    pub file_path: String,
+    /// Search index location, next to `file_path` by default
+    pub index_path: Option<String>,
+    /// Store raw messages next to `file_path`, so output can be regenerated with `render`
+    #[serde(default)]
+    pub store_raw: bool,
+    /// How sources are named in the "From" line
+    #[serde(default)]
+    pub source_format: SourceFormat,
+    /// When archived entries are flushed to disk
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+    /// Limit of telegram requests of the account, 10 by default, 0 disables the limit
+    pub requests_per_second: Option<f64>,
+    /// How long chats, users and supergroups are cached, in seconds; 1 hour by default, 0 disables the cache
+    pub metadata_cache_ttl: Option<u64>,
+    /// Keep cached chats, users and supergroups in this file across restarts
+    pub metadata_cache_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFormat {
+    /// Chat title or user's name
+    Title,
+    /// `@username`
+    #[default]
+    Username,
+    /// Title followed by `@username`
+    Both,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// Every entry is journaled and synced before the next one, an interrupted write is completed on restart
+    #[default]
+    Always,
+    /// Entries are synced on shutdown only, an entry truncated by a crash is cut off on restart
+    Never,
+}
+
+/// A problem found by [`Config::check`], `path` points to the offending value, e.g. `accounts[0].phone`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigProblem {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl Config {
+    /// Reads and parses the config, parse errors include the YAML path.
+    ///
+    /// `${NAME}` in values is replaced with the environment variable, and secrets set as `*_file`
+    /// are read from their files.
+    pub fn load<P: AsRef<path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).context(format!("read config {}", path.display()))?;
+        let mut config = Self::parse(&content, &|name| std::env::var(name).ok())
+            .context(format!("parse config {}", path.display()))?;
+        config.read_secret_files().context(format!("read secrets of config {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Parses the config with `${NAME}` replaced with `lookup(NAME)`. Syntax errors point to a line of `content`,
+    /// the interpolated value is deserialized as is, so other errors point to the YAML path.
+    fn parse<F: Fn(&str) -> Option<String>>(content: &str, lookup: &F) -> Result<Self> {
+        let mut value: Value = serde_yaml::from_str(content)?;
+        interpolate(&mut value, "", lookup)?;
+        serde_path_to_error::deserialize(value).map_err(|err| {
+            let path = err.path().to_string();
+            anyhow!("{}: {}", path_or_root(path.trim_start_matches('.')), err.inner())
+        })
+    }
+
+    fn read_secret_files(&mut self) -> Result<()> {
+        read_secret_file("telegram.api_hash", &mut self.telegram.api_hash, &self.telegram.api_hash_file)?;
+        if let Some(admin) = &mut self.admin {
+            let mut token = admin.token.take().unwrap_or_default();
+            read_secret_file("admin.token", &mut token, &admin.token_file)?;
+            admin.token = (!token.is_empty()).then_some(token);
+        }
+        for (i, account) in self.accounts.iter_mut().enumerate() {
+            read_secret_file(&format!("accounts[{}].phone", i), &mut account.phone, &account.phone_file)?;
+            let mut password = account.password.take().unwrap_or_default();
+            read_secret_file(&format!("accounts[{}].password", i), &mut password, &account.password_file)?;
+            account.password = (!password.is_empty()).then_some(password);
+        }
+        Ok(())
+    }
+
+    /// Validates values which parse but cannot work, reports every problem found.
+    pub fn check(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        let mut problem = |path: String, message: String| problems.push(ConfigProblem { path, message });
+
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            problem(
+                "log_level".to_string(),
+                format!("unknown level {:?}, expected off, error, warn, info, debug or trace", self.log_level),
+            );
+        }
+        if let Some(http) = &self.http {
+            if http.listen.parse::<std::net::SocketAddr>().is_err() {
+                problem(
+                    "http.listen".to_string(),
+                    format!("{:?} is not an address like 127.0.0.1:9090", http.listen),
+                );
+            }
+        }
+        if let Some(admin) = &self.admin {
+            match admin.listen.parse::<std::net::SocketAddr>() {
+                Err(_) => problem(
+                    "admin.listen".to_string(),
+                    format!("{:?} is not an address like 127.0.0.1:9091", admin.listen),
+                ),
+                // anyone who can reach the admin API can take over accounts with auth codes
+                Ok(addr) if !addr.ip().is_loopback() && admin.token.as_deref().unwrap_or("").is_empty() => problem(
+                    "admin.listen".to_string(),
+                    format!("{} is not a loopback address, set admin.token to require it", admin.listen),
+                ),
+                Ok(_) => {}
+            }
+        }
+        if self.telegram.api_id <= 0 {
+            problem("telegram.api_id".to_string(), "must be positive".to_string());
+        }
+        if self.telegram.api_hash.trim().is_empty() {
+            problem("telegram.api_hash".to_string(), "must not be empty".to_string());
+        }
+        if self.accounts.is_empty() {
+            problem("accounts".to_string(), "no accounts configured".to_string());
+        }
+
+        let mut phones: HashMap<&str, usize> = HashMap::new();
+        let mut tddb_dirs: HashMap<path::PathBuf, usize> = HashMap::new();
+        for (i, account) in self.accounts.iter().enumerate() {
+            if !is_valid_phone(&account.phone) {
+                problem(
+                    format!("accounts[{}].phone", i),
+                    format!("{:?} is not a phone number in international format, e.g. +79991234567", account.phone),
+                );
+            }
+            if let Some(first) = phones.insert(&account.phone, i) {
+                problem(format!("accounts[{}].phone", i), format!("same as accounts[{}].phone", first));
+            }
+
+            if let Err(err) = check_writable_file(path::Path::new(&account.file_path)) {
+                problem(format!("accounts[{}].file_path", i), err);
+            }
+            if let Some(index_path) = &account.index_path {
+                if let Err(err) = check_writable_file(path::Path::new(index_path)) {
+                    problem(format!("accounts[{}].index_path", i), err);
+                }
+            }
+            if let Some(cache_file) = &account.metadata_cache_file {
+                if let Err(err) = check_writable_file(path::Path::new(cache_file)) {
+                    problem(format!("accounts[{}].metadata_cache_file", i), err);
+                }
+            }
+
+            if account.requests_per_second() < 0.0 {
+                problem(format!("accounts[{}].requests_per_second", i), "must not be negative".to_string());
+            }
+
+            let tddb_dir = path::Path::new(&account.tddb_dir);
+            if account.tddb_dir.trim().is_empty() {
+                problem(format!("accounts[{}].tddb_dir", i), "must not be empty".to_string());
+            } else if let Err(err) = check_writable_dir(tddb_dir) {
+                problem(format!("accounts[{}].tddb_dir", i), err);
+            }
+            // two clients on the same database corrupt each other's session
+            if let Some(first) = tddb_dirs.insert(tddb_dir.components().collect(), i) {
+                problem(format!("accounts[{}].tddb_dir", i), format!("same as accounts[{}].tddb_dir", first));
+            }
+        }
+        problems
+    }
+}
+
+/// Replaces every `${NAME}` in string values with `lookup(NAME)`, `$${` stays as a literal `${`. Keys and comments
+/// are left as is, `path` is the YAML path of `value` for errors.
+///
+/// A value which is just a placeholder becomes a number or a boolean if the replacement is one,
+/// so `api_id: ${API_ID}` works, other replacements stay strings whatever characters they contain.
+fn interpolate<F: Fn(&str) -> Option<String>>(value: &mut Value, path: &str, lookup: &F) -> Result<()> {
+    match value {
+        Value::String(text) => {
+            if !text.contains("${") {
+                return Ok(());
+            }
+            let name = path_or_root(path);
+            let replaced = interpolate_str(text, lookup).map_err(|err| anyhow!("{}: {}", name, err))?;
+            let is_placeholder = text.starts_with("${") && text.find('}') == Some(text.len() - 1);
+            *value = if is_placeholder { typed_scalar(replaced) } else { Value::String(replaced) };
+        }
+        Value::Sequence(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate(item, &format!("{}[{}]", path, i), lookup)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (key, item) in mapping.iter_mut() {
+                let key = key.as_str().unwrap_or("?");
+                let path = if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+                interpolate(item, &path, lookup)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate(&mut tagged.value, path, lookup)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str<F: Fn(&str) -> Option<String>>(text: &str, lookup: &F) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| anyhow!("unterminated ${{"))?;
+        let name = &rest[start + 2..start + end];
+        let value = lookup(name).ok_or_else(|| anyhow!("environment variable {} is not set", name))?;
+        result.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Deserializes a secret which YAML may read as a number or a boolean, e.g. an all-digit password, as written.
+fn string_or_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: From<String> + Default,
+{
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(T::default()),
+        Value::String(text) => Ok(T::from(text)),
+        Value::Number(number) => Ok(T::from(number.to_string())),
+        Value::Bool(b) => Ok(T::from(b.to_string())),
+        _ => Err(serde::de::Error::custom("expected a string")),
+    }
+}
+
+/// A number or a boolean if `text` is written exactly like one, e.g. `12345` but not `+79991234567` or `007`.
+fn typed_scalar(text: String) -> Value {
+    match serde_yaml::from_str::<Value>(&text) {
+        Ok(Value::Number(number)) if number.to_string() == text => Value::Number(number),
+        Ok(Value::Bool(b)) if b.to_string() == text => Value::Bool(b),
+        _ => Value::String(text),
+    }
+}
+
+fn path_or_root(path: &str) -> &str {
+    if path.is_empty() {
+        "config"
+    } else {
+        path
+    }
+}
+
+/// Sets `value` to the content of `file` without the trailing newline, `name` is the YAML path of the value.
+fn read_secret_file(name: &str, value: &mut String, file: &Option<String>) -> Result<()> {
+    let Some(file) = file else {
+        return Ok(());
+    };
+    if !value.is_empty() {
+        bail!("{}: set either {} or {}_file, not both", name, name, name);
+    }
+    let content = fs::read_to_string(file).context(format!("{}_file: read {}", name, file))?;
+    *value = content.trim_end_matches(['\r', '\n']).to_string();
+    Ok(())
+}
+
+/// `+` followed by 7 to 15 digits, the leading `+` may be omitted.
+fn is_valid_phone(phone: &str) -> bool {
+    let digits = phone.strip_prefix('+').unwrap_or(phone);
+    (7..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Checks that the file can be appended to, or created if missing, without modifying it.
+fn check_writable_file(path: &path::Path) -> Result<(), String> {
+    if path.as_os_str().is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if path.is_dir() {
+        return Err(format!("{} is a directory", path.display()));
+    }
+    if path.exists() {
+        return fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(|_| ())
+            .map_err(|err| format!("{} is not writable: {}", path.display(), err));
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => check_writable_dir(parent),
+        _ => check_writable_dir(path::Path::new(".")),
+    }
+}
+
+/// Checks that files can be created in the directory, or in its closest existing ancestor if it
+/// doesn't exist yet, since it is created on start.
+fn check_writable_dir(path: &path::Path) -> Result<(), String> {
+    let Some(existing) = path.ancestors().find(|p| p.as_os_str().is_empty() || p.exists()) else {
+        return Err(format!("{} has no existing ancestor", path.display()));
+    };
+    let existing = if existing.as_os_str().is_empty() { path::Path::new(".") } else { existing };
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+    let probe = existing.join(format!(".teleforward-check-{}", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|err| format!("{} is not writable: {}", existing.display(), err))?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+impl AccountSettings {
+    pub fn index_path(&self) -> path::PathBuf {
+        match &self.index_path {
+            Some(p) => path::PathBuf::from(p),
+            None => default_index_path(&self.file_path),
+        }
+    }
+
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests_per_second.unwrap_or(DEFAULT_REQUESTS_PER_SECOND)
+    }
+
+    pub fn metadata_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.metadata_cache_ttl.unwrap_or(DEFAULT_METADATA_CACHE_TTL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        Config::parse(yaml, &|_| None).unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join(format!("teleforward-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.display();
+        let valid = config(&format!(
+            "accounts:\n  - {{phone: '+79991234567', file_path: {dir}/a/data.md, tddb_dir: {dir}/a/tddb}}\n\
+             telegram: {{api_id: 1, api_hash: hash}}\n\
+             admin: {{listen: '0.0.0.0:9091', token: secret}}\n"
+        ));
+        assert_eq!(valid.check(), vec![]);
+
+        let invalid = config(&format!(
+            "log_level: loud\n\
+             accounts:\n  - {{phone: '+7999', file_path: {dir}/a/data.md, tddb_dir: {dir}/tddb}}\n  \
+             - {{phone: '+79991234567', file_path: {dir}/b/data.md, tddb_dir: {dir}/tddb/}}\n\
+             telegram: {{api_id: 1, api_hash: ''}}\n\
+             admin: {{listen: '0.0.0.0:9091'}}\n"
+        ));
+        let paths = invalid.check().into_iter().map(|p| p.path).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec!["log_level", "admin.listen", "telegram.api_hash", "accounts[0].phone", "accounts[1].tddb_dir"]
+        );
+
+        let err = Config::parse(
+            &format!(
+            "accounts:\n  - {{phone: '+79991234567', file_path: {dir}/a/data.md, tddb_dir: {dir}/a/tddb, fsnyc: never}}\n\
+             telegram: {{api_id: 1, api_hash: hash}}\n"
+            ),
+            &|_| None,
+        )
+        .expect_err("unknown key accepted");
+        assert!(err.to_string().starts_with("accounts[0].fsnyc: unknown field `fsnyc`"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse() {
+        let lookup = |name: &str| (name == "API_ID").then(|| "12345".to_string());
+        let parsed = Config::parse(
+            "accounts:\n  - {phone: '+79991234567', password: 12345, file_path: data.md, tddb_dir: tddb}\n\
+             telegram: {api_id: '${API_ID}', api_hash: 987}\n",
+            &lookup,
+        )
+        .unwrap();
+        assert_eq!(parsed.telegram.api_id, 12345);
+        assert_eq!(parsed.telegram.api_hash, "987");
+        assert_eq!(parsed.accounts[0].password.as_deref(), Some("12345"));
+
+        let err = Config::parse("accounts: []\ntelegram: {api_id: abc}\n", &lookup).unwrap_err();
+        assert_eq!(err.to_string(), "telegram.api_id: invalid type: string \"abc\", expected i32");
+
+        let token_file = std::env::temp_dir().join(format!("teleforward-token-{}", std::process::id()));
+        fs::write(&token_file, "secret\n").unwrap();
+        let mut parsed = Config::parse(
+            &format!(
+                "accounts: []\ntelegram: {{api_id: 1}}\nadmin: {{listen: '127.0.0.1:9091', token_file: {}}}\n",
+                token_file.display()
+            ),
+            &lookup,
+        )
+        .unwrap();
+        parsed.read_secret_files().unwrap();
+        assert_eq!(parsed.admin.and_then(|admin| admin.token).as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let lookup = |name: &str| match name {
+            "API_HASH" => Some("0123abcd".to_string()),
+            "API_ID" => Some("12345".to_string()),
+            "PHONE" => Some("+79991234567".to_string()),
+            "PASSWORD" => Some("007: secret\n# not a comment".to_string()),
+            _ => None,
+        };
+        let interpolated = |yaml: &str| {
+            let mut value: Value = serde_yaml::from_str(yaml).unwrap();
+            interpolate(&mut value, "", &lookup).map(|_| value)
+        };
+        let value = interpolated(
+            "# password: ${UNSET}\n\
+             telegram: {api_id: '${API_ID}', api_hash: '${API_HASH}'}\n\
+             accounts:\n  - {phone: '${PHONE}', password: '${PASSWORD}', file_path: '${API_ID}/data.md'}\n\
+             note: $${API_HASH}\n",
+        )
+        .unwrap();
+        let expected: Value = serde_yaml::from_str(
+            "telegram: {api_id: 12345, api_hash: 0123abcd}\n\
+             accounts:\n  - {phone: '+79991234567', password: \"007: secret\\n# not a comment\", \
+             file_path: 12345/data.md}\n\
+             note: ${API_HASH}\n",
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+
+        let err = interpolated("telegram: {api_id: 1}\naccounts:\n  - {password: '${MISSING}'}\n").unwrap_err();
+        assert_eq!(err.to_string(), "accounts[0].password: environment variable MISSING is not set");
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// Messages a dry run would archive, by content type.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ContentTotals {
+    pub messages: u64,
+    pub attachments: u64,
+    /// Size of attachments as reported by telegram, they are not downloaded
+    pub attachments_size: u64,
+}
+
+/// Collects what a dry run would archive: rendered entries are written to stdout or a report file
+/// instead of the data file, and counted by content type.
+pub struct DryRunReport {
+    output: Mutex<Box<dyn Write + Send>>,
+    totals: Mutex<BTreeMap<String, ContentTotals>>,
+}
+
+impl fmt::Debug for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DryRunReport").field("totals", &self.totals).finish()
+    }
+}
+
+impl DryRunReport {
+    /// Entries are written to `report` if set, to stdout otherwise.
+    pub fn new(report: Option<&str>) -> Result<Self> {
+        let output: Box<dyn Write + Send> = match report {
+            Some(path) => Box::new(BufWriter::new(File::create(path).context(format!("create report {}", path))?)),
+            None => Box::new(io::stdout()),
+        };
+        Ok(Self {
+            output: Mutex::new(output),
+            totals: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn add(&self, content_type: &str, entry: &str, attachment_size: Option<u64>) -> Result<()> {
+        self.output
+            .lock()
+            .expect("report lock poisoned")
+            .write_all(entry.as_bytes())
+            .context("write report")?;
+        let mut totals = self.totals.lock().expect("report lock poisoned");
+        let totals = totals.entry(content_type.to_string()).or_default();
+        totals.messages += 1;
+        if let Some(size) = attachment_size {
+            totals.attachments += 1;
+            totals.attachments_size += size;
+        }
+        Ok(())
+    }
+
+    pub fn totals(&self) -> BTreeMap<String, ContentTotals> {
+        self.totals.lock().expect("report lock poisoned").clone()
+    }
+
+    /// Flushes the report and prints totals per content type.
+    pub fn finish(&self) -> Result<()> {
+        self.output.lock().expect("report lock poisoned").flush().context("flush report")?;
+        let totals = self.totals();
+        println!();
+        println!("{:<16} {:>8} {:>12} {:>14}", "content type", "messages", "attachments", "bytes");
+        let mut sum = ContentTotals::default();
+        for (content_type, t) in totals.iter() {
+            println!("{:<16} {:>8} {:>12} {:>14}", content_type, t.messages, t.attachments, t.attachments_size);
+            sum.messages += t.messages;
+            sum.attachments += t.attachments;
+            sum.attachments_size += t.attachments_size;
+        }
+        println!("{:<16} {:>8} {:>12} {:>14}", "total", sum.messages, sum.attachments, sum.attachments_size);
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+use std::{fs, path};
+
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use rust_tdlib::types::{ChatType, Message, MessageContent, MessageOrigin, MessageReplyTo, User, Usernames};
+
+use crate::account::ClientWithMeta;
+use crate::config::SourceFormat;
+use crate::error::AppError;
+use crate::index::{attachments_stats, IndexEntry};
+use crate::logging::mask_phone;
+use crate::metrics::metrics;
+use crate::raw::RawEntry;
+use crate::render::{content_type_name, make_snippet, message_formatted_text, origin_kind, parse_message_content, render_entry};
+
+/// Message converted for archiving: resolved metadata and rendered content.
+#[derive(Debug)]
+pub struct Entry {
+    pub raw: RawEntry,
+    /// Rendered message content, without the date and context lines
+    pub body: String,
+    pub date: NaiveDateTime,
+}
+
+impl Entry {
+    /// Complete Markdown entry as it is appended to the archive.
+    pub fn render(&self) -> Result<String> {
+        render_entry(&self.raw, &self.body)
+    }
+
+    pub fn index_entry(&self, account: &str, data_dir: &path::Path) -> IndexEntry {
+        let message = &self.raw.message;
+        let (attachments, attachments_size) = attachments_stats(data_dir, &self.body);
+        IndexEntry {
+            account: account.to_string(),
+            message_id: message.id(),
+            date: self.date,
+            source: self.raw.channel_name.clone(),
+            content_type: content_type_name(message.content()).to_string(),
+            origin: origin_kind(message).map(|o| o.to_string()),
+            attachments,
+            attachments_size,
+            text: self.body.clone(),
+        }
+    }
+}
+
+/// Downloads attachments and resolves metadata of the message, unless `message_meta` was already resolved.
+/// Returns `None` if the message has nothing to archive.
+pub async fn make_entry(
+    message: &Message,
+    client_meta: &ClientWithMeta,
+    message_meta: Option<MessageMeta>,
+) -> Result<Option<Entry>> {
+    // nothing to archive, don't waste requests on downloads and metadata
+    if parse_message_content(message.content(), None).is_none() {
+        return Ok(None);
+    }
+    // TODO: if a message contains more than one photo - actually there are several messages with the same media_album_id.
+    // we need kind of debounce here
+    let attachment = match client_meta.dry_run {
+        // nothing is downloaded in a dry run, the entry links the file it would have
+        Some(_) => attachment_info(message.content()).map(|(name, _)| name),
+        None => download_and_count(client_meta, message.content()).await?,
+    };
+    let message_meta = match message_meta {
+        Some(message_meta) => message_meta,
+        None => get_message_meta(message, client_meta)
+            .await
+            .context("cannot get message meta")?,
+    };
+    let raw = RawEntry {
+        message: message.clone(),
+        channel_name: message_meta.channel_name,
+        channel_link: message_meta.channel_link,
+        message_link: message_meta.message_link,
+        attachment,
+        reply_snippet: get_reply_snippet(message, client_meta).await,
+        tags: Vec::new(),
+    };
+    Ok(parse_message_content(message.content(), raw.attachment.as_deref()).map(|body| Entry {
+        raw,
+        body,
+        date: message_meta.message_date,
+    }))
+}
+
+async fn download_and_count(client_meta: &ClientWithMeta, content: &MessageContent) -> Result<Option<String>> {
+    let attachment = match download_attachment(client_meta, content).await {
+        Ok(attachment) => attachment,
+        Err(err) => {
+            metrics().download_failures.with_label_values(&[&mask_phone(&client_meta.phone)]).inc();
+            return Err(err.context("cannot download attachment"));
+        }
+    };
+    if let Some(file_name) = &attachment {
+        let size = fs::metadata(client_meta.markdown.data_dir()?.join(file_name))
+            .map(|m| m.len())
+            .unwrap_or(0);
+        metrics().download_bytes.with_label_values(&[&mask_phone(&client_meta.phone)]).inc_by(size);
+    }
+    Ok(attachment)
+}
+
+/// Quoted part of the replied message or the beginning of its text.
+pub async fn get_reply_snippet(message: &Message, client_meta: &ClientWithMeta) -> Option<String> {
+    let reply = match message.reply_to() {
+        Some(MessageReplyTo::Message(reply)) => reply,
+        _ => return None,
+    };
+    match reply.quote() {
+        Some(quote) => Some(make_snippet(quote.text())),
+        None => match client_meta.client.get_message(reply.chat_id(), reply.message_id()).await {
+            Ok(replied) => message_formatted_text(replied.content()).map(|t| make_snippet(t.text())),
+            Err(err) => {
+                log::debug!("cannot get replied message {}: {}", reply.message_id(), err);
+                None
+            }
+        },
+    }
+}
+
+pub struct MessageMeta {
+    pub channel_name: Option<String>,
+    pub channel_link: Option<String>,
+    pub message_link: Option<String>,
+    pub message_date: NaiveDateTime,
+}
+
+/// Chat or user a message comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub id: i64,
+    /// Chat title or user's full name
+    pub title: String,
+    pub username: Option<String>,
+}
+
+impl Source {
+    /// Name shown in the "From" line; the numeric id is added when there is no username to tell sources apart.
+    pub fn name(&self, format: SourceFormat) -> String {
+        match (format, &self.username) {
+            (SourceFormat::Title, Some(_)) => self.title.clone(),
+            (SourceFormat::Username, Some(username)) => format!("@{}", username),
+            (SourceFormat::Both, Some(username)) => format!("{} (@{})", self.title, username),
+            (_, None) if self.title.is_empty() => self.id.to_string(),
+            (_, None) => format!("{} ({})", self.title, self.id),
+        }
+    }
+
+    /// Public link to the chat, only sources with a username have one.
+    pub fn link(&self) -> Option<String> {
+        self.username.as_ref().map(|username| format!("https://t.me/{}", username))
+    }
+}
+
+pub async fn get_message_meta(message: &Message, client_meta: &ClientWithMeta) -> Result<MessageMeta> {
+    let (source, link_request) = match message.forward_info() {
+        None => {
+            if message.chat_id() == client_meta.chat_id {
+                return Ok(MessageMeta {
+                    channel_name: Some("SavedMessages".to_string()),
+                    channel_link: None,
+                    message_link: None,
+                    message_date: NaiveDateTime::from_timestamp_opt(message.date() as i64, 0)
+                        .context("cannot parse message date")?,
+                });
+            }
+            (
+                get_chat_source(client_meta, message.chat_id()).await.context("telegram:get chat source")?,
+                Some((message.chat_id(), message.id())),
+            )
+        }
+        Some(forward_info) => {
+            match forward_info.origin() {
+                MessageOrigin::_Default => {
+                    return Err(AppError::UnsupportedMessageOrigin.into());
+                }
+                MessageOrigin::Channel(channel) => {
+                    (
+                        get_chat_source(client_meta, channel.chat_id()).await.context("telegram:get channel source")?,
+                        Some((forward_info.from_chat_id(), forward_info.from_message_id()))
+                    )
+                }
+                MessageOrigin::Chat(chat) => {
+                    (
+                        get_chat_source(client_meta, chat.sender_chat_id()).await.context("telegram:get chat source")?,
+                        None,
+                    )
+                }
+                MessageOrigin::HiddenUser(hu) => {
+                    return Ok(MessageMeta {
+                        channel_name: Some(hu.sender_name().clone()),
+                        channel_link: None,
+                        message_link: None,
+                        message_date: NaiveDateTime::from_timestamp_opt(message.date() as i64, 0)
+                            .context("cannot parse message date")?,
+                    });
+                }
+                MessageOrigin::User(user) => {
+                    let user = client_meta.client.get_user(user.sender_user_id()).await?;
+                    (
+                        user_source(&user),
+                        None,
+                    )
+                }
+            }
+        }
+    };
+
+    let mut link = None;
+    if let Some((chat_id, message_id)) = link_request {
+        if let Ok(resp) = client_meta.client.get_message_link(chat_id, message_id).await {
+            link = Some(resp)
+        }
+    };
+
+    Ok(MessageMeta {
+        channel_name: Some(source.name(client_meta.source_format)),
+        channel_link: source.link(),
+        message_link: link,
+        message_date: NaiveDateTime::from_timestamp_opt(message.date() as i64, 0)
+            .context("cannot parse message date")?,
+    })
+}
+
+async fn get_chat_source(client_meta: &ClientWithMeta, chat_id: i64) -> Result<Source> {
+    let chat = client_meta.client.get_chat(chat_id).await?;
+    let username = match chat.type_() {
+        ChatType::_Default => {
+            return Err(AppError::UnsupportedChatType("unknown").into());
+        }
+        ChatType::Supergroup(sg) => {
+            let sg = client_meta.client.get_supergroup(sg.supergroup_id()).await?;
+            get_username(sg.usernames())
+        }
+        ChatType::BasicGroup(_) => None,
+        ChatType::Private(pr) => {
+            let u = client_meta.client.get_user(pr.user_id()).await?;
+            get_username(u.usernames())
+        }
+        ChatType::Secret(_) => {
+            return Err(AppError::UnsupportedChatType("secret").into());
+        }
+    };
+    Ok(Source {
+        id: chat_id,
+        // for private chats the title is the user's name
+        title: chat.title().clone(),
+        username,
+    })
+}
+
+fn user_source(user: &User) -> Source {
+    Source {
+        id: user.id(),
+        title: format!("{} {}", user.first_name(), user.last_name()).trim().to_string(),
+        username: get_username(user.usernames()),
+    }
+}
+
+pub fn get_username(usernames: &Option<Usernames>) -> Option<String> {
+    usernames
+        .iter()
+        .find_map(|s| s.active_usernames().first().cloned())
+}
+
+/// Name and size of the file attached to the message, without downloading it.
+/// Photos get a placeholder name, TDLib names them on download.
+pub fn attachment_info(content: &MessageContent) -> Option<(String, u64)> {
+    let size = |file: &rust_tdlib::types::File| file.size().max(file.expected_size()).max(0) as u64;
+    match content {
+        MessageContent::MessageDocument(message_document) => {
+            let doc = message_document.document();
+            Some((doc.file_name().clone(), size(doc.document())))
+        }
+        MessageContent::MessagePhoto(photo) => {
+            let photo_size = photo.photo().sizes().first()?;
+            Some((format!("photo_{}.jpg", photo_size.photo().id()), size(photo_size.photo())))
+        }
+        _ => None,
+    }
+}
+
+/// Downloads a file attached to the message into the data directory and returns its name.
+/// Returns `None` if the message has no attachment or it cannot be moved to the data directory.
+pub async fn download_attachment(client_meta: &ClientWithMeta, content: &MessageContent) -> Result<Option<String>> {
+    match content {
+        MessageContent::MessageDocument(message_document) => {
+            let doc = message_document.document();
+            log::info!("downloading file: {}", doc.file_name());
+            let local_path = client_meta.client.download_file(doc.document().id()).await?;
+            log::info!("downloaded file: {}", doc.file_name());
+            let path = client_meta.markdown.data_dir()?.join(doc.file_name());
+            fs::rename(local_path, &path).context("move downloaded file")?;
+            Ok(Some(doc.file_name().clone()))
+        }
+        MessageContent::MessagePhoto(photo) => {
+            log::info!("downloading photo");
+            let size = photo.photo().sizes().first().context("photo has no sizes")?;
+            // TODO: choose a particular image size: https://core.telegram.org/api/files#image-thumbnail-types
+            let local_path = client_meta.client.download_file(size.photo().id()).await?;
+            let file_name = match path::Path::new(&local_path).file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => return Ok(None),
+            };
+            let path = client_meta.markdown.data_dir()?.join(&file_name);
+            match fs::rename(&local_path, &path) {
+                Ok(_) => {
+                    log::debug!("downloaded photo to {:?}", path.to_str());
+                    Ok(Some(file_name))
+                }
+                Err(err) => {
+                    log::error!("cannot move file: {}", err);
+                    metrics().download_failures.with_label_values(&[&mask_phone(&client_meta.phone)]).inc();
+                    Ok(None)
+                }
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Source;
+    use crate::config::SourceFormat;
+
+    #[test]
+    fn test_source_name() {
+        let public = Source {
+            id: -100123,
+            title: "Rust News".to_string(),
+            username: Some("rustnews".to_string()),
+        };
+        let private = Source {
+            id: -100456,
+            title: "Family".to_string(),
+            username: None,
+        };
+        let tests = vec![
+            (&public, SourceFormat::Title, "Rust News"),
+            (&public, SourceFormat::Username, "@rustnews"),
+            (&public, SourceFormat::Both, "Rust News (@rustnews)"),
+            (&private, SourceFormat::Title, "Family (-100456)"),
+            (&private, SourceFormat::Username, "Family (-100456)"),
+            (&private, SourceFormat::Both, "Family (-100456)"),
+        ];
+        for (source, format, expected) in tests {
+            assert_eq!(source.name(format), expected);
+        }
+        assert_eq!(public.link().as_deref(), Some("https://t.me/rustnews"));
+        assert_eq!(private.link(), None);
+    }
+}
@@ -0,0 +1,27 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("wait code")]
+    WaitCode,
+    #[error("wait password")]
+    WaitPassword,
+    #[error("phone number is not registered, sign up with an official Telegram app first")]
+    RegistrationRequired,
+    #[error("not logged in: {0}")]
+    NotLoggedIn(String),
+    #[error("tdlib error")]
+    TdlibError(#[from] rust_tdlib::errors::Error),
+    #[error("client is not authorized, state: {0}")]
+    NotAuthorized(String),
+    #[error("cannot wait for auth state changes: {0}")]
+    AuthStateUnavailable(String),
+    #[error("unsupported chat type: {0}")]
+    UnsupportedChatType(&'static str),
+    #[error("unsupported message origin")]
+    UnsupportedMessageOrigin,
+    #[error("account already exists: {0}")]
+    AccountExists(String),
+    #[error("account not found: {0}")]
+    AccountNotFound(String),
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::account::{AccountState, Accounts};
+use crate::logging::mask_phone;
+use crate::metrics::metrics;
+
+#[derive(Debug, Serialize)]
+struct Health {
+    healthy: bool,
+    reader_running: bool,
+    accounts: Vec<AccountHealth>,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountHealth {
+    /// Masked, the listener has no authentication
+    phone: String,
+    #[serde(flatten)]
+    state: AccountState,
+}
+
+/// Serves `/healthz` and `/metrics` until shutdown.
+pub async fn serve(listen: &str, accounts: Arc<Accounts>, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(prometheus_metrics))
+        .with_state(accounts);
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .context(format!("listen on {}", listen))?;
+    log::info!("http listener on {}", listen);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        })
+        .await
+        .context("http server")
+}
+
+/// 200 if the updates reader and all accounts are running, 503 otherwise.
+async fn healthz(State(accounts): State<Arc<Accounts>>) -> impl IntoResponse {
+    let accounts = accounts
+        .states()
+        .into_iter()
+        .map(|(phone, state)| AccountHealth {
+            phone: mask_phone(&phone),
+            state,
+        })
+        .collect::<Vec<_>>();
+    let reader_running = metrics().reader_up.get() == 1;
+    let healthy = reader_running && accounts.iter().all(|a| a.state == AccountState::Running);
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (
+        status,
+        Json(Health {
+            healthy,
+            reader_running,
+            accounts,
+        }),
+    )
+}
+
+async fn prometheus_metrics() -> impl IntoResponse {
+    match metrics().encode() {
+        Ok(body) => (StatusCode::OK, body),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_healthz_masks_phones() {
+        let accounts = Arc::new(Accounts::default());
+        accounts.set_state("+79991234567", AccountState::Running);
+        let response = healthz(State(accounts)).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("cannot read body");
+        let body = String::from_utf8(body.to_vec()).expect("body is not utf-8");
+        assert!(body.contains(r#""phone":"+79*******67""#), "{}", body);
+        assert!(!body.contains("+79991234567"));
+    }
+}
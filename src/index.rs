@@ -0,0 +1,506 @@
+use std::sync::{Mutex, MutexGuard};
+use std::{fs, path};
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::config::Config;
+use crate::logging::mask_phone;
+
+pub const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Bumped whenever columns change, outdated indexes are recreated and have to be rebuilt with `reindex`.
+const SCHEMA_VERSION: i32 = 2;
+
+/// Archived entry as it is stored in the full-text index.
+#[derive(Debug)]
+pub struct IndexEntry {
+    pub account: String,
+    pub message_id: i64,
+    pub date: NaiveDateTime,
+    pub source: Option<String>,
+    pub content_type: String,
+    /// Kind of forward origin, `None` for messages that are not forwarded
+    pub origin: Option<String>,
+    pub attachments: u64,
+    pub attachments_size: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SearchQuery {
+    pub query: String,
+    pub account: Option<String>,
+    pub source: Option<String>,
+    pub content_type: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub limit: usize,
+}
+
+#[derive(Debug)]
+pub struct SearchResult {
+    pub account: String,
+    pub message_id: i64,
+    pub date: NaiveDateTime,
+    pub source: Option<String>,
+    pub content_type: String,
+    pub snippet: String,
+}
+
+/// Full-text index over archived entries, backed by SQLite FTS5.
+#[derive(Debug)]
+pub struct SearchIndex {
+    conn: Mutex<Connection>,
+}
+
+impl SearchIndex {
+    /// Opens the index for writing, creating it if needed. An index with an outdated schema is recreated empty,
+    /// it has to be rebuilt with `reindex`.
+    pub fn open<P: AsRef<path::Path>>(path: P) -> Result<Self> {
+        Self::create(Connection::open(path).context("open index database")?)
+    }
+
+    /// Empty index which is not stored anywhere, e.g. for a dry run.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::create(Connection::open_in_memory().context("open index database")?)
+    }
+
+    fn create(conn: Connection) -> Result<Self> {
+        let version = schema_version(&conn)?;
+        if version != SCHEMA_VERSION && has_entries_table(&conn)? {
+            log::warn!("search index schema is outdated, run `teleforward reindex` to rebuild it");
+            conn.execute_batch("DROP TABLE IF EXISTS entries;")
+                .context("drop outdated index")?;
+        }
+        conn.execute_batch(&format!(
+            r#"
+CREATE VIRTUAL TABLE IF NOT EXISTS entries USING fts5(
+    text,
+    source,
+    account UNINDEXED,
+    message_id UNINDEXED,
+    date UNINDEXED,
+    content_type UNINDEXED,
+    origin UNINDEXED,
+    attachments UNINDEXED,
+    attachments_size UNINDEXED
+);
+PRAGMA user_version = {};
+"#,
+            SCHEMA_VERSION
+        ))
+        .context("create index tables")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Opens an existing index for `search` and `stats`, nothing is created or migrated.
+    pub fn open_read_only<P: AsRef<path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            bail!("no search index at {}, run `teleforward reindex` to build it", path.display());
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context("open index database")?;
+        if schema_version(&conn)? != SCHEMA_VERSION || !has_entries_table(&conn)? {
+            bail!(
+                "search index {} is outdated, run `teleforward reindex` to rebuild it",
+                path.display()
+            );
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().expect("index lock poisoned")
+    }
+
+    /// Adds the entry, replacing one indexed before for the same message.
+    pub fn add(&self, entry: &IndexEntry) -> Result<()> {
+        let mut conn = self.conn.lock().expect("index lock poisoned");
+        let tx = conn.transaction().context("begin transaction")?;
+        delete(&tx, &entry.account, entry.message_id)?;
+        insert(&tx, entry)?;
+        tx.commit().context("commit transaction")
+    }
+
+    pub fn remove(&self, account: &str, message_id: i64) -> Result<()> {
+        let conn = self.conn.lock().expect("index lock poisoned");
+        delete(&conn, account, message_id)
+    }
+
+    /// Replaces all entries of the account in a single transaction.
+    pub fn rebuild(&self, account: &str, entries: &[IndexEntry]) -> Result<()> {
+        let mut conn = self.conn.lock().expect("index lock poisoned");
+        let tx = conn.transaction().context("begin transaction")?;
+        tx.execute("DELETE FROM entries WHERE account = ?1", params![account])
+            .context("clear index")?;
+        for entry in entries {
+            insert(&tx, entry)?;
+        }
+        tx.commit().context("commit transaction")?;
+        Ok(())
+    }
+
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+        let conn = self.conn.lock().expect("index lock poisoned");
+        let mut stmt = conn
+            .prepare(
+                r#"
+SELECT account, message_id, date, source, content_type, snippet(entries, 0, '**', '**', '…', 16)
+FROM entries
+WHERE entries MATCH ?1
+  AND (?2 IS NULL OR account = ?2)
+  AND (?3 IS NULL OR source = ?3)
+  AND (?4 IS NULL OR content_type = ?4)
+  AND (?5 IS NULL OR date >= ?5)
+  AND (?6 IS NULL OR date < ?6)
+ORDER BY rank
+LIMIT ?7
+"#,
+            )
+            .context("prepare search query")?;
+        let rows = stmt
+            .query_map(
+                params![
+                    query.query,
+                    query.account,
+                    query.source,
+                    query.content_type,
+                    query.since.map(|d| d.timestamp()),
+                    query.until.map(|d| d.timestamp()),
+                    query.limit as i64,
+                ],
+                |row| {
+                    Ok(SearchResult {
+                        account: row.get(0)?,
+                        message_id: row.get(1)?,
+                        date: NaiveDateTime::from_timestamp_opt(row.get(2)?, 0).unwrap_or_default(),
+                        source: row.get(3)?,
+                        content_type: row.get(4)?,
+                        snippet: row.get(5)?,
+                    })
+                },
+            )
+            .context("search")?;
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row.context("read search result")?);
+        }
+        Ok(results)
+    }
+}
+
+fn schema_version(conn: &Connection) -> Result<i32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .context("get schema version")
+}
+
+/// A new database has version 0 and no tables, it isn't outdated.
+fn has_entries_table(conn: &Connection) -> Result<bool> {
+    let count: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'entries'",
+            [],
+            |row| row.get(0),
+        )
+        .context("check index tables")?;
+    Ok(count > 0)
+}
+
+/// FTS5 tables have no unique keys, so `add` deletes an entry of the same message before inserting.
+/// The unindexed columns make this a table scan, which `rebuild` avoids by clearing the account once.
+fn delete(conn: &Connection, account: &str, message_id: i64) -> Result<()> {
+    conn.execute(
+        "DELETE FROM entries WHERE account = ?1 AND message_id = ?2",
+        params![account, message_id],
+    )
+    .context("delete index entry")?;
+    Ok(())
+}
+
+fn insert(conn: &Connection, entry: &IndexEntry) -> Result<()> {
+    conn.execute(
+        r#"
+INSERT INTO entries (text, source, account, message_id, date, content_type, origin, attachments, attachments_size)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+"#,
+        params![
+            entry.text,
+            entry.source,
+            entry.account,
+            entry.message_id,
+            entry.date.timestamp(),
+            entry.content_type,
+            entry.origin,
+            entry.attachments as i64,
+            entry.attachments_size as i64,
+        ],
+    )
+    .context("insert index entry")?;
+    Ok(())
+}
+
+/// Searches indexes of the configured accounts, or only of `query.account`, and prints matches with their
+/// location in the archive.
+pub fn search_accounts(config: &Config, query: &SearchQuery) -> Result<()> {
+    for account in config.accounts.iter() {
+        if query.account.as_ref().is_some_and(|phone| phone != &account.phone) {
+            continue;
+        }
+        let index =
+            SearchIndex::open_read_only(account.index_path()).context(format!("open index of {}", mask_phone(&account.phone)))?;
+        let results = index
+            .search(&SearchQuery {
+                account: Some(account.phone.clone()),
+                ..query.clone()
+            })
+            .context("search")?;
+        for result in results {
+            println!(
+                "{} {}#msg-{} [{}] {} {}: {}",
+                result.account,
+                account.file_path,
+                result.message_id,
+                result.content_type,
+                result.date.format(DATE_FORMAT),
+                result.source.as_deref().unwrap_or("-"),
+                result.snippet.replace('\n', " "),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Rebuilds indexes of the configured accounts from their Markdown archives.
+pub fn reindex(config: &Config) -> Result<()> {
+    for account in config.accounts.iter() {
+        let content = fs::read_to_string(&account.file_path).context(format!("read {}", &account.file_path))?;
+        let data_dir = path::Path::new(&account.file_path).parent().context("data dir")?;
+        let entries = entries_from_markdown(&account.phone, data_dir, &content);
+        let index = SearchIndex::open(account.index_path()).context(format!("open index of {}", mask_phone(&account.phone)))?;
+        index.rebuild(&account.phone, &entries).context("rebuild index")?;
+        log::info!("indexed {} entries of {}", entries.len(), mask_phone(&account.phone));
+    }
+    Ok(())
+}
+
+/// Default index location: next to the data file, e.g. `data/data.md` -> `data/data.sqlite`.
+pub fn default_index_path(file_path: &str) -> path::PathBuf {
+    path::Path::new(file_path).with_extension("sqlite")
+}
+
+/// Counts files embedded into an entry as `![](name)` and their total size on disk.
+pub fn attachments_stats(data_dir: &path::Path, text: &str) -> (u64, u64) {
+    let mut count = 0;
+    let mut size = 0;
+    let mut rest = text;
+    while let Some(start) = rest.find("![](") {
+        rest = &rest[start + 4..];
+        let Some(end) = rest.find(')') else { break };
+        count += 1;
+        size += fs::metadata(data_dir.join(&rest[..end]))
+            .map(|m| m.len())
+            .unwrap_or(0);
+        rest = &rest[end..];
+    }
+    (count, size)
+}
+
+fn anchor_attribute<'a>(anchor: &'a str, name: &str) -> Option<&'a str> {
+    anchor
+        .split_once(&format!(r#"{}=""#, name))
+        .and_then(|(_, value)| value.split('"').next())
+}
+
+/// Parses entries of an existing Markdown archive. Entries written before anchors were introduced are skipped.
+pub fn entries_from_markdown(account: &str, data_dir: &path::Path, content: &str) -> Vec<IndexEntry> {
+    const ANCHOR_PREFIX: &str = r#"<a id="msg-"#;
+    let mut entries = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(ANCHOR_PREFIX) {
+        let entry = &rest[start..];
+        let end = entry[ANCHOR_PREFIX.len()..]
+            .find(ANCHOR_PREFIX)
+            .map(|i| i + ANCHOR_PREFIX.len())
+            .unwrap_or(entry.len());
+        if let Some(parsed) = parse_markdown_entry(account, data_dir, &entry[..end]) {
+            entries.push(parsed);
+        }
+        rest = &entry[end..];
+    }
+    entries
+}
+
+fn parse_markdown_entry(account: &str, data_dir: &path::Path, entry: &str) -> Option<IndexEntry> {
+    let (anchor, body) = entry.split_once('\n')?;
+    let message_id = anchor
+        .strip_prefix(r#"<a id="msg-"#)?
+        .split('"')
+        .next()?
+        .parse::<i64>()
+        .ok()?;
+    let content_type = anchor_attribute(anchor, "data-type")
+        .unwrap_or("unknown")
+        .to_string();
+    let origin = anchor_attribute(anchor, "data-origin").map(|o| o.to_string());
+
+    let mut source = None;
+    let mut date = None;
+    let mut text_lines = Vec::new();
+    let mut in_reply = false;
+    for line in body.lines() {
+        // the quoted snippet of the replied message is context, live indexing doesn't include it either
+        if std::mem::take(&mut in_reply) && line.starts_with("> ") {
+            continue;
+        }
+        if line.starts_with("**In reply to:** ") {
+            in_reply = true;
+            continue;
+        }
+        if let Some(from) = line.strip_prefix("**From:** ") {
+            // linked sources are written as `[name](link)`
+            let from = match from.strip_prefix('[').and_then(|f| f.rsplit_once("](")) {
+                Some((name, _)) => name,
+                None => from,
+            };
+            source = Some(from.to_string());
+        } else if let Some(d) = line.strip_prefix("**Date:** ") {
+            let d = d.trim_start_matches('[');
+            let d = d.split(']').next().unwrap_or(d);
+            date = NaiveDateTime::parse_from_str(d, DATE_FORMAT).ok();
+        } else if ["**Tags:** ", "**Topic:** ", "**Thread:** "]
+            .iter()
+            .any(|prefix| line.starts_with(prefix))
+            || line.trim() == "---"
+        {
+            continue;
+        } else {
+            text_lines.push(line);
+        }
+    }
+    let text = text_lines.join("\n").trim().to_string();
+    let (attachments, attachments_size) = attachments_stats(data_dir, &text);
+    Some(IndexEntry {
+        account: account.to_string(),
+        message_id,
+        date: date?,
+        source,
+        content_type,
+        origin,
+        attachments,
+        attachments_size,
+        text,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use chrono::NaiveDate;
+
+    use super::{entries_from_markdown, parse_markdown_entry, IndexEntry, SearchIndex, SearchQuery};
+
+    const ENTRIES: &str = "<a id=\"msg-10\" data-type=\"text\"></a>\n\
+        **Tags:** #rust\n\n\
+        **From:** [@rustnews](https://t.me/rustnews)\n\n\
+        **Date:** [2023-11-14 22:13:20](https://t.me/rustnews/1)\n\n\
+        hello **world**\n\n---\n\n\
+        <a id=\"msg-11\" data-type=\"photo\" data-origin=\"channel\"></a>\n\
+        **Date:** 2023-11-14 22:13:21\n\n\
+        **In reply to:** [message 10](#msg-10)\n\
+        > hello world\n\n\
+        ![](photo_5.jpg)\n\
+        caption\n\n---\n\n";
+
+    #[test]
+    fn test_parse_markdown_entry() {
+        let first = &ENTRIES[..ENTRIES.find(r#"<a id="msg-11""#).unwrap()];
+        let entry = parse_markdown_entry("+1", path::Path::new("/nonexistent"), first).expect("entry not parsed");
+        assert_eq!(entry.message_id, 10);
+        assert_eq!(entry.source.as_deref(), Some("@rustnews"));
+        assert_eq!(entry.content_type, "text");
+        assert_eq!(entry.origin, None);
+        assert_eq!(
+            entry.date,
+            NaiveDate::from_ymd_opt(2023, 11, 14).unwrap().and_hms_opt(22, 13, 20).unwrap()
+        );
+        assert_eq!(entry.text, "hello **world**");
+
+        // entries written before anchors and dates can't be indexed
+        assert!(parse_markdown_entry("+1", path::Path::new("/nonexistent"), "hello\n\n---\n").is_none());
+        assert!(parse_markdown_entry("+1", path::Path::new("/nonexistent"), "<a id=\"msg-1\"></a>\nno date").is_none());
+    }
+
+    #[test]
+    fn test_entries_from_markdown() {
+        let content = format!("old entry\n\n---\n\n{}", ENTRIES);
+        let entries = entries_from_markdown("+1", path::Path::new("/nonexistent"), &content);
+        assert_eq!(entries.len(), 2);
+        let reply = &entries[1];
+        assert_eq!(reply.message_id, 11);
+        assert_eq!(reply.content_type, "photo");
+        assert_eq!(reply.origin.as_deref(), Some("channel"));
+        assert_eq!(reply.source, None);
+        // the reply snippet is not part of the text
+        assert_eq!(reply.text, "![](photo_5.jpg)\ncaption");
+        assert_eq!((reply.attachments, reply.attachments_size), (1, 0));
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let path = std::env::temp_dir().join(format!("teleforward-index-ro-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(SearchIndex::open_read_only(&path).is_err());
+        assert!(!path.exists());
+
+        SearchIndex::open(&path).expect("cannot create index");
+        SearchIndex::open_read_only(&path).expect("cannot open index");
+
+        // an outdated index is reported, not dropped
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch("PRAGMA user_version = 1;")
+            .unwrap();
+        let err = SearchIndex::open_read_only(&path).expect_err("outdated index opened");
+        assert!(err.to_string().contains("reindex"));
+    }
+
+    #[test]
+    fn test_add_replaces_entry() {
+        let path = std::env::temp_dir().join(format!("teleforward-index-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let index = SearchIndex::open(&path).expect("cannot open index");
+        let entry = |text: &str| IndexEntry {
+            account: "+1".to_string(),
+            message_id: 1,
+            date: NaiveDate::from_ymd_opt(2023, 11, 14).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            source: None,
+            content_type: "text".to_string(),
+            origin: None,
+            attachments: 0,
+            attachments_size: 0,
+            text: text.to_string(),
+        };
+        index.add(&entry("first version")).expect("cannot add");
+        index.add(&entry("second version")).expect("cannot add");
+
+        let search = |query: &str| {
+            index
+                .search(&SearchQuery {
+                    query: query.to_string(),
+                    limit: 10,
+                    ..Default::default()
+                })
+                .expect("search failed")
+        };
+        assert_eq!(search("version").len(), 1);
+        assert!(search("first").is_empty());
+    }
+}
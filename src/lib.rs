@@ -0,0 +1,24 @@
+//! Archives messages saved to Telegram "Saved Messages" into a local Markdown file.
+//!
+//! The `teleforward` binary is a thin CLI over this crate, other services can embed the archiver
+//! through [`account`] or reuse the renderer and sinks separately.
+
+pub mod account;
+pub mod admin;
+pub mod commands;
+pub mod config;
+pub mod dry_run;
+pub mod entry;
+pub mod error;
+pub mod http;
+pub mod index;
+pub mod logging;
+pub mod metrics;
+pub mod raw;
+pub mod registry;
+pub mod reload;
+pub mod render;
+pub mod sink;
+pub mod skip_list;
+pub mod stats;
+pub mod telegram;
@@ -0,0 +1,202 @@
+use std::future::Future;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Context, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use rust_tdlib::tdjson;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::{Config, TelegramConfig};
+
+const TDLIB_LOG_MAX_FILE_SIZE: i64 = 10 * 1024 * 1024;
+
+/// `log_level` of teleforward's own logs, kept outside of env_logger so it can be changed on reload.
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Error as usize);
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+tokio::task_local! {
+    static LOG_CONTEXT: LogContext;
+}
+
+/// Fields attached to every log line written while processing an account's messages.
+#[derive(Debug, Clone, Default)]
+pub struct LogContext {
+    /// Masked phone of the account
+    pub account: Option<String>,
+    pub client_id: Option<i32>,
+    pub chat_id: Option<i64>,
+    pub message_id: Option<i64>,
+}
+
+impl LogContext {
+    pub fn account(phone: &str, client_id: i32, chat_id: i64) -> Self {
+        Self {
+            account: Some(mask_phone(phone)),
+            client_id: Some(client_id),
+            chat_id: Some(chat_id),
+            message_id: None,
+        }
+    }
+
+    pub fn with_message(self, message_id: i64) -> Self {
+        Self {
+            message_id: Some(message_id),
+            ..self
+        }
+    }
+
+    /// Runs `f` with this context attached to its logs.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        LOG_CONTEXT.scope(self, f).await
+    }
+
+    fn current() -> Option<Self> {
+        LOG_CONTEXT.try_with(|context| context.clone()).ok()
+    }
+}
+
+/// Keeps the country code and the last two digits, e.g. `+79991234567` -> `+79*******67`.
+pub fn mask_phone(phone: &str) -> String {
+    let chars = phone.chars().collect::<Vec<_>>();
+    if chars.len() <= 5 {
+        return "*".repeat(chars.len());
+    }
+    chars
+        .iter()
+        .enumerate()
+        .map(|(i, c)| if i < 3 || i >= chars.len() - 2 { *c } else { '*' })
+        .collect()
+}
+
+/// Passes teleforward's logs at `LOG_LEVEL` and other crates' logs at `warn` to env_logger.
+struct Logger {
+    inner: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.target().starts_with(env!("CARGO_PKG_NAME")) {
+            metadata.level() <= level()
+        } else {
+            metadata.level() <= LevelFilter::Warn
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn level() -> LevelFilter {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Changes the level of teleforward's own logs.
+pub fn set_level(level: LevelFilter) {
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level.max(LevelFilter::Warn));
+}
+
+pub fn setup(config: &Config) -> Result<()> {
+    let format = config.log_format;
+    let level = LevelFilter::from_str(&config.log_level).context("parse loglevel")?;
+    let inner = env_logger::Builder::new()
+        // levels are checked by `Logger`
+        .filter_level(LevelFilter::Trace)
+        .format(move |buf, record| {
+            let context = LogContext::current().unwrap_or_default();
+            match format {
+                LogFormat::Json => {
+                    let line = json!({
+                        "timestamp": buf.timestamp().to_string(),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                        "account": context.account,
+                        "client_id": context.client_id,
+                        "chat_id": context.chat_id,
+                        "message_id": context.message_id,
+                    });
+                    writeln!(buf, "{}", line)
+                }
+                LogFormat::Text => {
+                    write!(buf, "[{} {:<5} {}] {}", buf.timestamp(), record.level(), record.target(), record.args())?;
+                    if let Some(account) = &context.account {
+                        write!(buf, " account={}", account)?;
+                    }
+                    if let Some(client_id) = context.client_id {
+                        write!(buf, " client_id={}", client_id)?;
+                    }
+                    if let Some(chat_id) = context.chat_id {
+                        write!(buf, " chat_id={}", chat_id)?;
+                    }
+                    if let Some(message_id) = context.message_id {
+                        write!(buf, " message_id={}", message_id)?;
+                    }
+                    writeln!(buf)
+                }
+            }
+        })
+        .build();
+    log::set_boxed_logger(Box::new(Logger { inner })).context("set logger")?;
+    set_level(level);
+    setup_tdlib_logging(&config.telegram)
+}
+
+/// TDLib writes to stderr unless `tdlib_log_file` is set.
+fn setup_tdlib_logging(telegram: &TelegramConfig) -> Result<()> {
+    tdjson::set_log_verbosity_level(telegram.tdlib_log_verbosity);
+    let Some(path) = &telegram.tdlib_log_file else {
+        return Ok(());
+    };
+    let request = json!({
+        "@type": "setLogStream",
+        "log_stream": {
+            "@type": "logStreamFile",
+            "path": path,
+            "max_file_size": TDLIB_LOG_MAX_FILE_SIZE,
+            "redirect_stderr": false,
+        },
+    });
+    match tdjson::execute(&request.to_string()) {
+        Some(response) if response.contains(r#""@type":"error""#) => {
+            Err(anyhow!("cannot set tdlib log stream: {}", response))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mask_phone;
+
+    #[test]
+    fn test_mask_phone() {
+        assert_eq!(mask_phone("+79991234567"), "+79*******67");
+        assert_eq!(mask_phone("+1234"), "*****");
+    }
+}
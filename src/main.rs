@@ -1,30 +1,31 @@
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::str::FromStr;
-use std::sync::{Arc, OnceLock};
-use std::{env, fs, path};
-
-use anyhow::{Context, Result};
-use async_trait::async_trait;
+use std::fs;
+use std::path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
-use clap::{Args, Parser, Subcommand};
-use log::LevelFilter;
-use rust_tdlib::client::auth_handler::ClientAuthStateHandler;
-use rust_tdlib::client::tdlib_client::TdJson;
-use rust_tdlib::client::{AuthStateHandlerProxy, ClientIdentifier, ClientState};
-use rust_tdlib::types::{AuthorizationState, AuthorizationStateWaitCode, AuthorizationStateWaitPassword, AuthorizationStateWaitPhoneNumber, AuthorizationStateWaitRegistration, ChatType, CreatePrivateChat, DownloadFile, GetChat, GetChatHistory, GetMessageLink, GetSupergroup, GetUser, Message, MessageOrigin, RObject, Usernames};
-use rust_tdlib::types::{FormattedText, GetMe, MessageContent, TextEntity, TextEntityType};
-use rust_tdlib::{
-    client::{Client, Worker},
-    tdjson,
-    types::{SetTdlibParameters, Update},
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rust_tdlib::client::AuthStateHandlerProxy;
+use rust_tdlib::{client::Worker, types::Update};
+use tokio::signal::unix::{signal, SignalKind};
+
+use teleforward::account::{
+    auth_clients, create_updates_reader, log_out, replay_fixture, session_status, setup_client, sync, Accounts,
+    SessionStatus, SyncOptions,
 };
-use serde::Deserialize;
-use thiserror::Error;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
-use tokio::task::JoinHandle;
+use teleforward::config::Config;
+use teleforward::admin;
+use teleforward::dry_run::DryRunReport;
+use teleforward::error::AppError;
+use teleforward::http;
+use teleforward::index::{self, SearchQuery};
+use teleforward::logging::{self, mask_phone};
+use teleforward::registry::AccountRegistry;
+use teleforward::reload::reload;
+use teleforward::render;
+use teleforward::stats;
+use teleforward::telegram::record::Recorder;
 
 #[derive(Parser)]
 struct Cli {
@@ -39,111 +40,258 @@ enum Commands {
     /// Initialize clients
     Init(Input),
     /// Runs the main routine
-    Run,
+    Run(RunArgs),
     /// Synchronizes history
-    Sync,
+    Sync(SyncArgs),
+    /// Searches archived messages
+    Search(SearchArgs),
+    /// Rebuilds search index from existing output
+    Reindex,
+    /// Reports statistics of archived messages
+    Stats(StatsArgs),
+    /// Regenerates output from stored raw messages, without network access
+    Render(RenderArgs),
+    /// Replays a recorded fixture against the pipeline, without network access
+    Replay(ReplayArgs),
+    /// Works with the config file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manages TDLib sessions of accounts, `run` must be stopped first
+    #[command(subcommand)]
+    Accounts(AccountsCommand),
 }
 
-#[derive(Args)]
-struct Input {
-    phones_to_codes: Option<String>,
+#[derive(Subcommand)]
+enum AccountsCommand {
+    /// Shows whether each account is authorized, and as whom, without requesting auth codes
+    Status,
+    /// Terminates the account's session
+    Logout(LogoutArgs),
 }
 
-static ACCOUNTS_DATA: OnceLock<HashMap<i32, ClientWithMeta>> = OnceLock::new();
-
-#[derive(Debug)]
-struct ClientWithMeta {
-    chat_id: i64,
-    file: Arc<Mutex<File>>,
-    client: Client<TdJson>,
-    file_path: String,
+#[derive(Args)]
+struct LogoutArgs {
+    phone: String,
+    /// Also remove the account's `tddb_dir`
+    #[arg(long)]
+    wipe: bool,
 }
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    accounts: Vec<AccountSettings>,
-    telegram: TelegramConfig,
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Validates the config and reports every problem found
+    Check,
+}
 
-    #[serde(default = "default_loglevel")]
-    log_level: String,
+#[derive(Args)]
+struct SyncArgs {
+    /// Only the account with this phone
+    #[arg(long)]
+    account: Option<String>,
+    /// Only messages sent at or after this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    since: Option<NaiveDateTime>,
+    /// Only messages sent before this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    until: Option<NaiveDateTime>,
+    /// At most this many messages per account, newest first
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Only messages from this source, as written in the "From" line
+    #[arg(long)]
+    source: Option<String>,
+    #[command(flatten)]
+    dry_run: DryRunArgs,
 }
 
-#[derive(Debug, Deserialize)]
-struct TelegramConfig {
-    #[serde(default = "default_verbosity")]
-    tdlib_log_verbosity: i32,
+#[derive(Args)]
+struct RunArgs {
+    /// Record received updates and telegram responses to this fixture file
+    #[arg(long)]
+    record: Option<String>,
+    #[command(flatten)]
+    dry_run: DryRunArgs,
+}
 
-    api_id: i32,
-    api_hash: String,
+#[derive(Args)]
+struct DryRunArgs {
+    /// Render entries to stdout instead of the data file, without downloading attachments, and print totals
+    #[arg(long)]
+    dry_run: bool,
+    /// Write entries of the dry run to this file instead of stdout
+    #[arg(long, requires = "dry_run")]
+    report: Option<String>,
 }
 
-const fn default_verbosity() -> i32 {
-    1
+#[derive(Args)]
+struct ReplayArgs {
+    /// Fixture recorded with `run --record`
+    fixture: String,
+    /// Directory for the output, one file per recorded client
+    #[arg(long, default_value = "replay")]
+    output_dir: String,
 }
 
-fn default_loglevel() -> String {
-    "error".to_string()
+#[derive(Args)]
+struct RenderArgs {
+    /// Only the account with this phone
+    #[arg(long)]
+    account: Option<String>,
+    /// Write to this file instead of `data.rendered.md` next to account's `file_path`, requires `--account`
+    #[arg(long, requires = "account")]
+    output: Option<String>,
+    /// Replace account's `file_path`, refused if it has entries missing from the raw store
+    #[arg(long, conflicts_with = "output")]
+    in_place: bool,
 }
 
-#[derive(Deserialize, Debug)]
-struct AccountSettings {
-    phone: String,
-    tddb_dir: String,
-    file_path: String,
+#[derive(Args)]
+struct StatsArgs {
+    /// Only the account with this phone
+    #[arg(long)]
+    account: Option<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
 }
 
-#[derive(Debug, Clone)]
-struct ClientAuthorizer {
-    phone: String,
-    auth_code: Option<String>,
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
 }
 
-#[async_trait]
-impl ClientAuthStateHandler for ClientAuthorizer {
-    async fn handle_wait_code(&self, _wait_code: &AuthorizationStateWaitCode) -> String {
-        match &self.auth_code {
-            None => {
-                log::warn!("auth code is needed for {}", self.phone);
-                "".to_string()
-            }
-            Some(code) => code.clone(),
-        }
-    }
+#[derive(Args)]
+struct SearchArgs {
+    /// Full-text query, FTS5 syntax is supported
+    query: String,
+    /// Only messages archived by the account with this phone
+    #[arg(long)]
+    account: Option<String>,
+    /// Only messages from this source, as written in the "From" line
+    #[arg(long)]
+    source: Option<String>,
+    /// Only messages of this type: text, photo, document, animation, audio, video, voice_note
+    #[arg(long = "type")]
+    content_type: Option<String>,
+    /// Only messages sent at or after this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    since: Option<NaiveDateTime>,
+    /// Only messages sent before this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    until: Option<NaiveDateTime>,
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+}
 
-    async fn handle_wait_password(
-        &self,
-        _wait_password: &AuthorizationStateWaitPassword,
-    ) -> String {
-        unimplemented!("password is not supported")
-    }
+fn parse_date(s: &str) -> Result<NaiveDateTime> {
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").context("expected YYYY-MM-DD")?;
+    Ok(date.and_time(NaiveTime::MIN))
+}
 
-    async fn handle_wait_client_identifier(
-        &self,
-        _: &AuthorizationStateWaitPhoneNumber,
-    ) -> ClientIdentifier {
-        ClientIdentifier::PhoneNumber(self.phone.clone())
-    }
+#[derive(Args)]
+struct Input {
+    phones_to_codes: Option<String>,
+}
 
-    async fn handle_wait_registration(
-        &self,
-        _wait_registration: &AuthorizationStateWaitRegistration,
-    ) -> (String, String) {
-        unimplemented!("registration is not supported")
+/// Parses `phone:code` lines of `init`.
+fn parse_phones_to_codes(s: &str) -> Result<HashMap<String, String>> {
+    let mut codes = HashMap::new();
+    for (i, line) in s.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (phone, code) = line
+            .split_once(':')
+            .context(format!("phones_to_codes line {}: expected `phone:code`", i + 1))?;
+        codes.insert(phone.to_string(), code.to_string());
     }
+    Ok(codes)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let f = File::open(cli.config).expect("Could not open file.");
-    let config: Config = serde_yaml::from_reader(f).expect("Could not read values.");
+    let config = Config::load(&cli.config)?;
+    // commands working with local data only don't need credentials or telegram databases
+    let offline = matches!(
+        cli.command,
+        Commands::Search(_) | Commands::Reindex | Commands::Stats(_) | Commands::Render(_) | Commands::Replay(_)
+    );
+    if !offline {
+        let problems = config.check();
+        if let Commands::Config(ConfigCommand::Check) = &cli.command {
+            if problems.is_empty() {
+                println!("{}: ok", cli.config);
+                return Ok(());
+            }
+        }
+        if !problems.is_empty() {
+            for problem in problems.iter() {
+                eprintln!("{}: {}", cli.config, problem);
+            }
+            bail!("{} has {} problem(s), see `teleforward config check`", cli.config, problems.len());
+        }
+    }
+
+    logging::setup(&config).context("logging setup")?;
+
+    // commands working with local data only, no telegram client is needed
+    match &cli.command {
+        Commands::Search(args) => {
+            let query = SearchQuery {
+                query: args.query.clone(),
+                account: args.account.clone(),
+                source: args.source.clone(),
+                content_type: args.content_type.clone(),
+                since: args.since,
+                until: args.until,
+                limit: args.limit,
+            };
+            return index::search_accounts(&config, &query);
+        }
+        Commands::Reindex => return index::reindex(&config),
+        Commands::Stats(args) => {
+            let format = match args.format {
+                OutputFormat::Table => stats::OutputFormat::Table,
+                OutputFormat::Json => stats::OutputFormat::Json,
+            };
+            return stats::print_accounts(&config, args.account.as_deref(), format);
+        }
+        Commands::Render(args) => {
+            return render::render_accounts(&config, args.account.as_deref(), args.output.as_deref(), args.in_place)
+        }
+        Commands::Replay(args) => {
+            let outputs = replay_fixture(path::Path::new(&args.fixture), path::Path::new(&args.output_dir)).await?;
+            for output in outputs {
+                println!("{}", output.display());
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let recorder = match &cli.command {
+        Commands::Run(args) => match &args.record {
+            Some(path) => Some(Arc::new(Recorder::create(path).context("create recorder")?)),
+            None => None,
+        },
+        _ => None,
+    };
 
-    setup_logging(&config.log_level, config.telegram.tdlib_log_verbosity).context("logging setup")?;
+    let dry_run = match &cli.command {
+        Commands::Run(RunArgs { dry_run, .. }) | Commands::Sync(SyncArgs { dry_run, .. }) if dry_run.dry_run => {
+            Some(Arc::new(DryRunReport::new(dry_run.report.as_deref()).context("create dry run report")?))
+        }
+        _ => None,
+    };
 
     let (sender, receiver) = tokio::sync::mpsc::channel::<Box<Update>>(100);
 
-    let reader = create_updates_reader(receiver);
+    let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+
+    let accounts = Arc::new(Accounts::default());
+    let reader = create_updates_reader(receiver, accounts.clone(), recorder.clone(), shutdown.clone());
 
     let mut worker = Worker::builder()
         .with_auth_state_handler(AuthStateHandlerProxy::default())
@@ -152,17 +300,12 @@ async fn main() -> Result<()> {
 
     match &cli.command {
         Commands::Init(arg) => {
-            let mut codes = HashMap::new();
-            arg.phones_to_codes.as_ref().map(|s| {
-                for line in s.lines() {
-                    let mut split = line.splitn(2, ':');
-                    let phone = split.next().unwrap().to_string();
-                    let code = split.next().unwrap().to_string();
-                    codes.insert(phone, code);
-                }
-            });
+            let codes = match &arg.phones_to_codes {
+                Some(s) => parse_phones_to_codes(s)?,
+                None => HashMap::new(),
+            };
 
-            let auth_resp = auth_clients(config, &mut worker, sender.clone(), codes).await;
+            let auth_resp = auth_clients(config, &accounts, &mut worker, sender.clone(), codes, None).await;
             worker.stop();
             waiter.await?;
 
@@ -179,25 +322,105 @@ async fn main() -> Result<()> {
                 },
             };
         }
-        Commands::Run => {
-            auth_clients(config, &mut worker, sender.clone(), HashMap::new()).await.context("cannot authorize clients")?;
-            tokio::select! {
-                _ = waiter => {log::warn!("worker stopped")}
-                res = reader => {
-                    match res {
-                        Ok(_) => {
-                            log::info!("reader stopped");
+        Commands::Run(_) => {
+            if let Some(http_config) = &config.http {
+                let listen = http_config.listen.clone();
+                let accounts = accounts.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = http::serve(&listen, accounts, shutdown).await {
+                        log::error!("http listener failed: {:#}", err);
+                    }
+                });
+            }
+            // every account is supervised separately, so one failing account doesn't stop others
+            let worker = Arc::new(tokio::sync::Mutex::new(worker));
+            let registry = Arc::new(AccountRegistry::new(
+                worker.clone(),
+                config.telegram.api_id,
+                config.telegram.api_hash.clone(),
+                sender.clone(),
+                accounts.clone(),
+                recorder.clone(),
+                dry_run.clone(),
+            ));
+            for account in config.accounts.iter() {
+                registry.add(account.clone()).await?;
+            }
+            if let Some(admin_config) = &config.admin {
+                let listen = admin_config.listen.clone();
+                let token = admin_config.token.clone().filter(|token| !token.is_empty());
+                let registry = registry.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = admin::serve(&listen, token, registry, shutdown).await {
+                        log::error!("admin listener failed: {:#}", err);
+                    }
+                });
+            }
+            let mut hangup = signal(SignalKind::hangup()).context("listen for SIGHUP")?;
+            let shutdown_requested = shutdown_signal();
+            tokio::pin!(shutdown_requested);
+            let mut config = config;
+            let mut reader = reader;
+            let mut waiter = waiter;
+            loop {
+                tokio::select! {
+                    _ = &mut waiter => {
+                        log::warn!("worker stopped");
+                        break;
+                    }
+                    res = &mut reader => {
+                        match res {
+                            Ok(_) => {
+                                log::info!("reader stopped");
+                            }
+                            Err(err) => {
+                                log::error!("reader panicked: {}", err);
+                            }
                         }
-                        Err(err) => {
-                            log::error!("reader error: {}", err);
+                        break;
+                    }
+                    res = &mut shutdown_requested => {
+                        res?;
+                        // stop intake and process updates which are already queued
+                        shutdown_sender.send_replace(true);
+                        if let Err(err) = reader.await {
+                            log::error!("reader panicked: {}", err);
+                        }
+                        break;
+                    }
+                    _ = hangup.recv() => {
+                        log::info!("SIGHUP received, reloading {}", cli.config);
+                        match reload(path::Path::new(&cli.config), &config, &registry).await {
+                            Ok(reloaded) => config = reloaded,
+                            Err(err) => log::error!("config is not reloaded, keeping the current one: {:#}", err),
                         }
                     }
                 }
-                _ = tokio::signal::ctrl_c() => {log::info!("ctrl-c received")}
             }
+            // flushes every account's output and closes its client
+            registry.stop_all().await;
+            worker.lock().await.stop();
+            if let Some(report) = &dry_run {
+                report.finish()?;
+            }
+            log::info!("stopped");
         }
-        Commands::Sync => {
+        Commands::Sync(args) => {
+            let options = SyncOptions {
+                since: args.since,
+                until: args.until,
+                limit: args.limit,
+                source: args.source.clone(),
+            };
+            let shutdown_requested = shutdown_signal();
+            tokio::pin!(shutdown_requested);
+            let mut interrupted = false;
             for account in config.accounts.iter() {
+                if args.account.as_ref().is_some_and(|phone| phone != &account.phone) {
+                    continue;
+                }
                 let acc_data = setup_client(
                     &mut worker,
                     account,
@@ -205,692 +428,104 @@ async fn main() -> Result<()> {
                     config.telegram.api_hash.clone(),
                     None,
                     None,
+                    account.password.as_ref(),
+                    None,
+                    dry_run.clone(),
                 )
-                .await.context(format!("{} client authorization", &account.phone))?;
-                sync(&acc_data).await.context(format!("sync {}", &account.phone))?;
-            }
-        }
-    }
-
-    Ok(())
-}
-
-async fn auth_clients(
-    config: Config,
-    worker: &mut Worker<AuthStateHandlerProxy, TdJson>,
-    sender: Sender<Box<Update>>,
-    codes: HashMap<String, String>,
-) -> Result<()> {
-    let mut accounts_data = HashMap::new();
-
-    for account in config.accounts.iter() {
-        let code = codes.get(&account.phone);
-        let client_data = setup_client(
-            worker,
-            account,
-            config.telegram.api_id,
-            config.telegram.api_hash.clone(),
-            Some(sender.clone()),
-            code,
-        )
-        .await.context(format!("setup client {}", &account.phone))?;
-
-        accounts_data.insert(
-            client_data
-                .client
-                .get_client_id()
-                .expect("client_id not set"),
-            client_data,
-        );
-    }
-    ACCOUNTS_DATA
-        .set(accounts_data)
-        .expect("accounts data already set");
-    Ok(())
-}
-
-fn setup_logging(log_level: &str, tdlib_log_verbosity: i32) -> Result<()> {
-    env_logger::Builder::new()
-        .filter_level(LevelFilter::from_str("warn").context("parse loglevel")?)
-        .filter_module(env!("CARGO_PKG_NAME"), LevelFilter::from_str(&log_level).context("parse loglevel")?)
-        .init();
-    tdjson::set_log_verbosity_level(tdlib_log_verbosity);
-    Ok(())
-}
-
-async fn setup_client(
-    worker: &mut Worker<AuthStateHandlerProxy, TdJson>,
-    account: &AccountSettings,
-    api_id: i32,
-    api_hash: String,
-    sender: Option<Sender<Box<Update>>>,
-    auth_code: Option<&String>,
-) -> Result<ClientWithMeta> {
-    let mut builder = Client::builder()
-        .with_tdlib_parameters(
-            SetTdlibParameters::builder()
-                .database_directory(&account.tddb_dir)
-                .use_test_dc(false)
-                .api_id(api_id)
-                .api_hash(api_hash)
-                .system_language_code("en")
-                .device_model("Desktop")
-                .system_version("Unknown")
-                .application_version(env!("CARGO_PKG_VERSION"))
-                .enable_storage_optimizer(true)
-                .build(),
-        )
-        .with_auth_state_channel(10)
-        .with_client_auth_state_handler(ClientAuthorizer {
-            phone: account.phone.clone(),
-            auth_code: auth_code.map(|s| s.clone()),
-        });
-    match sender {
-        None => {}
-        Some(sender) => {
-            builder = builder.with_updates_sender(sender);
-        }
-    }
-    let client = builder.build().context("client parameters setup")?;
-    let client = worker.bind_client(client).await.context("bind client to worker")?;
-    wait_authorized(&client, &worker).await.context("wait authorized")?;
-    log::debug!("{} authorized", account.phone);
-
-    let me = client.get_me(GetMe::builder().build()).await.context("telegram:get_me")?;
-    log::debug!("authorized as: {:?}", me);
-
-    let path = path::Path::new(account.file_path.as_str());
-    fs::create_dir_all(path.parent().unwrap()).context("create data dir")?;
-    let file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(account.file_path.as_str()).context("open data file")?;
-
-    Ok(ClientWithMeta {
-        chat_id: me.id(),
-        file: Arc::new(Mutex::new(file)),
-        client,
-        file_path: account.file_path.clone(),
-    })
-}
-
-async fn sync(acc_data: &ClientWithMeta) -> Result<()> {
-    // TODO: create backup
-    log::info!("start sync for client {}", acc_data.chat_id);
-    let mut from_msg_id = 0;
-    acc_data
-        .client
-        .create_private_chat(
-            CreatePrivateChat::builder()
-                .user_id(acc_data.chat_id)
-                .build(),
-        )
-        .await.context(r#"get "SavedMessages" chat"#)?;
-    let mut total_processed_messages = 0;
-    loop {
-        let messages = acc_data
-            .client
-            .get_chat_history(
-                GetChatHistory::builder()
-                    .chat_id(acc_data.chat_id)
-                    .offset(0)
-                    .from_message_id(from_msg_id)
-                    .limit(100),
-            )
-            .await.context("telegram:get_chat_history")?;
-        if messages.total_count() == 0 {
-            log::debug!("processed {} messages", total_processed_messages);
-            return Ok(());
-        }
-
-        for msg in messages.messages() {
-            match msg {
-                None => {}
-                Some(msg) => {
-                    total_processed_messages += 1;
-                    from_msg_id = msg.id();
-                    process_message(msg, acc_data).await.context("process message")?;
-                }
-            }
-        }
-        log::info!("processed {} messages", total_processed_messages,);
-    }
-}
-
-#[derive(Error, Debug)]
-enum AppError {
-    #[error("wait code")]
-    WaitCode,
-    #[error("tdlib error")]
-    TdlibError(#[from] rust_tdlib::errors::Error),
-}
-
-async fn wait_authorized(
-    client: &Client<TdJson>,
-    worker: &Worker<AuthStateHandlerProxy, TdJson>,
-) -> Result<(), AppError> {
-    loop {
-        match worker.wait_auth_state_change(&client).await.context("worker:wait_auth_state_change") {
-            Ok(res) => match res {
-                Ok(state) => match state {
-                    ClientState::Opened => {
-                        log::debug!("client authorized; can start interaction");
-                        break;
-                    }
-                    _ => {
-                        panic!("client not authorized: {:?}", state);
-                    }
-                },
-                Err((err, auth_state)) => {
-                    return match auth_state.authorization_state() {
-                        AuthorizationState::WaitCode(_) => Err(AppError::WaitCode)?,
-                        _ => Err(AppError::TdlibError(err))?,
+                .await.context(format!("{} client authorization", mask_phone(&account.phone)))?;
+                let synced = sync(&acc_data, &options);
+                tokio::pin!(synced);
+                let res = tokio::select! {
+                    res = &mut synced => res,
+                    res = &mut shutdown_requested => {
+                        res?;
+                        interrupted = true;
+                        // the sync stops at the next message, then archived entries are flushed
+                        acc_data.stopping.store(true, std::sync::atomic::Ordering::Relaxed);
+                        synced.await
                     }
+                };
+                res.context(format!("sync {}", mask_phone(&account.phone)))?;
+                acc_data.shutdown().await.context(format!("flush {}", mask_phone(&account.phone)))?;
+                if interrupted {
+                    log::info!("sync interrupted, skipping remaining accounts");
+                    break;
                 }
-            },
-            Err(err) => {
-                panic!("cannot wait for auth state changes: {}", err);
             }
-        }
-    }
-    Ok(())
-}
-
-fn create_updates_reader(mut receiver: Receiver<Box<Update>>) -> JoinHandle<Result<()>> {
-    tokio::spawn(async move {
-        while let Some(message) = receiver.recv().await {
-            match message.as_ref() {
-                Update::NewMessage(new_message) => match ACCOUNTS_DATA.get() {
-                    None => {
-                        log::debug!("accounts data is not set");
-                        continue;
-                    }
-                    Some(d) => {
-                        let client_id = new_message.client_id().unwrap_or(-1);
-                        match d.get(&client_id) {
-                            None => {
-                                log::error!("client_id not found: {}", client_id);
-                                continue;
-                            }
-                            Some(data) => {
-                                log::trace!("chat_id of message: {}, expected chat_id: {}", new_message.message().chat_id(), &data.chat_id);
-                                if &new_message.message().chat_id() != &data.chat_id {
-                                    continue;
-                                }
-                                process_message(new_message.message(), data).await.context("process message")?;
-                            }
-                        }
-                    }
-                },
-                _ => {}
+            if let Some(report) = &dry_run {
+                report.finish()?;
             }
         }
-        Ok(())
-    })
-}
-
-async fn process_message(message: &Message, client_meta: &ClientWithMeta) -> Result<()> {
-    log::trace!("message content: {:?}", message);
-    // TODO: if a message contains more than one photo - actually there are several messages with the same media_album_id.
-    // we need kind of debounce here
-    match parse_message_content(client_meta, message.content()).await {
-        Some(text) => {
-            let message_meta = match get_message_meta(message, client_meta).await {
-                Ok(m) => m,
-                Err(err) => {
-                    log::error!("cannot get message meta: {}", err);
-                    return Ok(());
-                }
-            };
-            let mut text = match message_meta.message_link {
-                Some(link) => {
-                    format!(
-                        r#"
-**Date:** [{message_date}]({message_link})
-
-{text}
-
----
-
-"#,
-                        message_date = message_meta.message_date.format("%Y-%m-%d %H:%M:%S"),
-                        message_link = link,
-                        text = text
-                    )
-                },
-                None => {
-                    format!(
-                        r#"
-**Date:** {message_date}
-
-{text}
-
----
-
-"#,
-                        message_date = message_meta.message_date.format("%Y-%m-%d %H:%M:%S"),
-                        text = text
-                    )
+        Commands::Accounts(AccountsCommand::Status) => {
+            for account in config.accounts.iter() {
+                let status = session_status(
+                    &mut worker,
+                    account,
+                    config.telegram.api_id,
+                    config.telegram.api_hash.clone(),
+                )
+                .await.context(format!("check {}", mask_phone(&account.phone)))?;
+                match status {
+                    SessionStatus::Authorized { user_id, name, username } => println!(
+                        "{}: authorized as {} ({}id {})",
+                        account.phone,
+                        name,
+                        username.map(|u| format!("@{}, ", u)).unwrap_or_default(),
+                        user_id,
+                    ),
+                    SessionStatus::NotAuthorized(reason) => println!("{}: not authorized, {}", account.phone, reason),
                 }
-            };
-            if let Some(n) = message_meta.channel_name {
-                text = format!("**From:** {}\n\n{}", n, text);
             }
-            client_meta.file.lock().await.write_all(text.as_bytes()).context("write to file")?;
+            worker.stop();
+            waiter.await?;
         }
-        None => {}
-    };
-    Ok(())
-}
-
-struct MessageMeta {
-    channel_name: Option<String>,
-    message_link: Option<String>,
-    message_date: NaiveDateTime,
-}
-
-async fn get_message_meta(message: &Message, client_meta: &ClientWithMeta) -> Result<MessageMeta> {
-    let (channel_name, link_request) = match message.forward_info() {
-        None => {
-            if message.chat_id() == client_meta.chat_id {
-                return Ok(MessageMeta {
-                    channel_name: Some("SavedMessages".to_string()),
-                    message_link: None,
-                    message_date: NaiveDateTime::from_timestamp_opt(message.date() as i64, 0)
-                        .context("cannot parse message date")?,
-                });
-            }
-            let chat = client_meta
-                .client
-                .get_chat(GetChat::builder().chat_id(message.chat_id()).build())
-                .await.context("telegram:get_chat")?;
-            let name = match chat.type_() {
-                ChatType::Supergroup(sg) => {
-                    let sg = client_meta
-                        .client
-                        .get_supergroup(GetSupergroup::builder().supergroup_id(sg.supergroup_id()))
-                        .await.context("telegram:get_supergroup")?;
-                    get_username(sg.usernames())
-                }
-                _ => None,
-            };
-            (
-                name,
-                Some(GetMessageLink::builder()
-                    .chat_id(message.chat_id())
-                    .message_id(message.id())
-                    .build())
+        Commands::Accounts(AccountsCommand::Logout(args)) => {
+            let account = config
+                .accounts
+                .iter()
+                .find(|account| account.phone == args.phone)
+                .context(format!("{} is not in the config", mask_phone(&args.phone)))?;
+            let logged_out = log_out(
+                &mut worker,
+                account,
+                config.telegram.api_id,
+                config.telegram.api_hash.clone(),
             )
-        }
-        Some(forward_info) => {
-            match forward_info.origin() {
-                MessageOrigin::_Default => {
-                    unreachable!("default message origin is not supported")
-                }
-                MessageOrigin::Channel(channel) => {
-                    (
-                        get_channel_name(client_meta, channel.chat_id()).await.context("telegram:get channel name")?,
-                        Some(GetMessageLink::builder()
-                            .chat_id(forward_info.from_chat_id())
-                            .message_id(forward_info.from_message_id())
-                            .build())
-                    )
-                }
-                MessageOrigin::Chat(chat) => {
-                    (
-                        get_channel_name(client_meta, chat.sender_chat_id()).await.context("telegram:get chat name")?,
-                        None,
-                    )
-                }
-                MessageOrigin::HiddenUser(hu) => {
-                    (
-                        Some(hu.sender_name().clone()),
-                        None,
-                    )
-                }
-                MessageOrigin::User(user) => {
-                    let user = client_meta.client.get_user(GetUser::builder().user_id(user.sender_user_id()).build()).await.context("telegram:get_user")?;
-                    (
-                        get_username(user.usernames()),
-                        None,
-                    )
-                }
-            }
-        }
-    };
-
-    let mut link = None;
-    if let Some(link_request) = link_request {
-        let link_resp = client_meta.client.get_message_link(link_request).await;
-        if let Ok(resp) = link_resp {
-            link = Some(resp.link().clone())
-        }
-    };
-
-    Ok(MessageMeta {
-        channel_name,
-        message_link: link,
-        message_date: NaiveDateTime::from_timestamp_opt(message.date() as i64, 0)
-            .context("cannot parse message date")?,
-    })
-}
-
-async fn get_channel_name(client_meta: &ClientWithMeta, chat_id: i64) -> Result<Option<String>> {
-    let chat = client_meta
-        .client
-        .get_chat(
-            GetChat::builder()
-                .chat_id(chat_id)
-                .build(),
-        )
-        .await.context("telegram:get_chat")?;
-    Ok(match chat.type_() {
-        ChatType::_Default => {
-            unreachable!("chat type is not supported")
-        }
-        ChatType::Supergroup(sg) => {
-            let sg = client_meta
-                .client
-                .get_supergroup(GetSupergroup::builder().supergroup_id(sg.supergroup_id()))
-                .await.context("telegram:get_supergroup")?;
-            get_username(sg.usernames())
-        }
-        ChatType::BasicGroup(_) => None,
-        ChatType::Private(pr) => {
-            let u = client_meta
-                .client
-                .get_user(GetUser::builder().user_id(pr.user_id()).build())
-                .await.context("telegram:get_user")?;
-            get_username(u.usernames())
-        }
-        ChatType::Secret(_) => {
-            unimplemented!("secret chat is not supported")
-        }
-    })
-}
-fn get_username(usernames: &Option<Usernames>) -> Option<String> {
-    usernames
-        .iter()
-        .next()
-        .map(|s| s.active_usernames().first().unwrap().clone())
-}
-
-async fn parse_message_content(
-    client_meta: &ClientWithMeta,
-    content: &MessageContent,
-) -> Option<String> {
-    match content {
-        MessageContent::MessageText(text) => return Some(parse_formatted_text(text.text())),
-        MessageContent::MessageAnimation(message_animation) => {
-            return Some(parse_formatted_text(message_animation.caption()));
-        }
-        MessageContent::MessageAudio(message_audio) => {
-            return Some(parse_formatted_text(message_audio.caption()));
-        }
-        MessageContent::MessageDocument(message_document) => {
-            let doc = message_document.document();
-            log::info!("downloading file: {}", doc.file_name());
-            let file = match client_meta
-                .client
-                .download_file(
-                    DownloadFile::builder()
-                        .file_id(doc.document().id())
-                        .synchronous(true)
-                        .priority(1)
-                        .build(),
-                )
-                .await
-            {
-                Ok(file) => file,
-                Err(err) => {
-                    log::error!("cannot download file: {}", err);
-                    return None;
-                }
-            };
-            log::info!("downloaded file: {}", doc.file_name());
-            let path = path::Path::new(client_meta.file_path.as_str())
-                .parent()?
-                .join(doc.file_name());
-            fs::rename(file.local().path(), &path).ok()?;
-            let mut parsed_text = parse_formatted_text(message_document.caption());
-            parsed_text.push_str(format!("\n\n![]({})", doc.file_name()).as_str());
-            return Some(parsed_text);
-        }
-        MessageContent::MessagePhoto(photo) => {
-            log::info!("downloading photo");
-            let file = match client_meta
-                .client
-                .download_file(
-                    DownloadFile::builder()
-                        // TODO: choose a particular image size: https://core.telegram.org/api/files#image-thumbnail-types
-                        .file_id(photo.photo().sizes().first().unwrap().photo().id())
-                        .synchronous(true)
-                        .priority(1)
-                        .build(),
-                )
-                .await
-            {
-                Ok(file) => file,
-                Err(err) => {
-                    log::error!("cannot download file: {}", err);
-                    return None;
-                }
-            };
-            let file_name = path::Path::new(file.local().path()).file_name().unwrap();
-            let path = path::Path::new(client_meta.file_path.as_str())
-                .parent()
-                .unwrap()
-                .join(&file_name);
-            match fs::rename(file.local().path(), &path) {
-                Ok(_) => {
-                    log::debug!("downloaded photo to {:?}", path.to_str());
-                    let mut parsed_text = format!("\n\n![]({})\n\n", file_name.to_str().unwrap());
-                    parsed_text.push_str(parse_formatted_text(photo.caption()).as_str());
-                    return Some(parsed_text);
-                }
-                Err(err) => {
-                    log::error!("cannot move file: {}", err);
-                    return Some(parse_formatted_text(photo.caption()))
-                }
+            .await.context(format!("log out {}", mask_phone(&account.phone)))?;
+            if logged_out {
+                println!("{}: logged out", account.phone);
+            } else {
+                println!("{}: not authorized", account.phone);
             }
-        }
-        MessageContent::MessageVideo(message_video) => {
-            return Some(parse_formatted_text(message_video.caption()));
-        }
-
-        // probably needs to be supported
-        MessageContent::MessageLocation(_) => {}
-        MessageContent::MessageVideoNote(_) => {}
-        MessageContent::MessageVoiceNote(_) => {}
-
-        MessageContent::_Default => {}
-        MessageContent::MessageAnimatedEmoji(_) => {}
-        MessageContent::MessageBasicGroupChatCreate(_) => {}
-        MessageContent::MessageCall(_) => {}
-        MessageContent::MessageChatAddMembers(_) => {}
-        MessageContent::MessageChatChangePhoto(_) => {}
-        MessageContent::MessageChatChangeTitle(_) => {}
-        MessageContent::MessageChatDeleteMember(_) => {}
-        MessageContent::MessageChatDeletePhoto(_) => {}
-        MessageContent::MessageChatJoinByLink(_) => {}
-        MessageContent::MessageChatJoinByRequest(_) => {}
-        MessageContent::MessageChatSetTheme(_) => {}
-        MessageContent::MessageChatUpgradeFrom(_) => {}
-        MessageContent::MessageChatUpgradeTo(_) => {}
-        MessageContent::MessageContact(_) => {}
-        MessageContent::MessageContactRegistered(_) => {}
-        MessageContent::MessageCustomServiceAction(_) => {}
-        MessageContent::MessageDice(_) => {}
-        MessageContent::MessageExpiredPhoto(_) => {}
-        MessageContent::MessageExpiredVideo(_) => {}
-        MessageContent::MessageGame(_) => {}
-        MessageContent::MessageGameScore(_) => {}
-        MessageContent::MessageInviteVideoChatParticipants(_) => {}
-        MessageContent::MessageInvoice(_) => {}
-        MessageContent::MessagePassportDataReceived(_) => {}
-        MessageContent::MessagePassportDataSent(_) => {}
-        MessageContent::MessagePaymentSuccessful(_) => {}
-        MessageContent::MessagePaymentSuccessfulBot(_) => {}
-        MessageContent::MessagePinMessage(_) => {}
-        MessageContent::MessagePoll(_) => {}
-        MessageContent::MessageProximityAlertTriggered(_) => {}
-        MessageContent::MessageScreenshotTaken(_) => {}
-        MessageContent::MessageSticker(_) => {}
-        MessageContent::MessageSupergroupChatCreate(_) => {}
-        MessageContent::MessageUnsupported(_) => {}
-        MessageContent::MessageVenue(_) => {}
-        MessageContent::MessageVideoChatEnded(_) => {}
-        MessageContent::MessageVideoChatScheduled(_) => {}
-        MessageContent::MessageVideoChatStarted(_) => {}
-        MessageContent::MessageBotWriteAccessAllowed(_) => {}
-        MessageContent::MessageChatSetBackground(_) => {}
-        MessageContent::MessageChatSetMessageAutoDeleteTime(_) => {}
-        MessageContent::MessageChatShared(_) => {}
-        MessageContent::MessageForumTopicCreated(_) => {}
-        MessageContent::MessageForumTopicEdited(_) => {}
-        MessageContent::MessageForumTopicIsClosedToggled(_) => {}
-        MessageContent::MessageForumTopicIsHiddenToggled(_) => {}
-        MessageContent::MessageGiftedPremium(_) => {}
-        MessageContent::MessagePremiumGiftCode(_) => {}
-        MessageContent::MessagePremiumGiveaway(_) => {}
-        MessageContent::MessagePremiumGiveawayCreated(_) => {}
-        MessageContent::MessageStory(_) => {}
-        MessageContent::MessageSuggestProfilePhoto(_) => {}
-        MessageContent::MessageUserShared(_) => {}
-        MessageContent::MessageWebAppDataReceived(_) => {}
-        MessageContent::MessageWebAppDataSent(_) => {}
-    }
-    None
-}
-
-fn parse_formatted_text(formatted_text: &FormattedText) -> String {
-    let mut entities_by_index = make_entities_stack(formatted_text.entities());
-    let mut result_text = String::new();
-    let mut current_entity = match entities_by_index.pop() {
-        None => return formatted_text.text().clone(),
-        Some(entity) => entity,
-    };
-    for (i, ch) in formatted_text.text().encode_utf16().enumerate() {
-        let c = char::from_u32(ch as u32).unwrap_or(' ').to_string();
-        if i == current_entity.0 {
-            result_text.push_str(current_entity.1.as_str());
-            current_entity = loop {
-                match entities_by_index.pop() {
-                    None => {
-                        result_text = format!(
-                            "{}{}",
-                            result_text,
-                            &formatted_text
-                                .text()
-                                .chars()
-                                .skip(i + 1)
-                                .take(formatted_text.text().len() - i)
-                                .collect::<String>()
-                        );
-                        return result_text;
-                    }
-                    Some(entity) => {
-                        if entity.0 == i {
-                            result_text.push_str(&entity.1);
-                        } else {
-                            result_text.push_str(c.as_str());
-                            break entity;
-                        }
-                    }
-                }
+            worker.stop();
+            waiter.await?;
+            if args.wipe {
+                fs::remove_dir_all(&account.tddb_dir).context(format!("remove {}", &account.tddb_dir))?;
+                println!("{}: removed {}", account.phone, account.tddb_dir);
             }
-        } else {
-            result_text.push_str(c.as_str());
         }
-    }
-
-    result_text.push_str(&current_entity.1);
-
-    loop {
-        match entities_by_index.pop() {
-            None => return result_text,
-            Some(entity) => {
-                result_text.push_str(&entity.1);
-            }
+        Commands::Search(_)
+        | Commands::Reindex
+        | Commands::Stats(_)
+        | Commands::Render(_)
+        | Commands::Replay(_)
+        | Commands::Config(_) => {
+            unreachable!("handled above")
         }
     }
-}
 
-fn make_entities_stack(entities: &[TextEntity]) -> Vec<(usize, String)> {
-    let mut stack = Vec::new();
-    for entity in entities {
-        let formatting = match entity.type_() {
-            TextEntityType::Bold(_) => Some(("**".to_string(), "**".to_string())),
-            TextEntityType::Code(_) => Some(("`".to_string(), "`".to_string())),
-            TextEntityType::Hashtag(_) => Some(("#".to_string(), "".to_string())),
-            TextEntityType::Italic(_) => Some(("<i>".to_string(), "</i>".to_string())),
-            TextEntityType::PhoneNumber(_) => Some(("<phone>".to_string(), "</phone>".to_string())),
-            TextEntityType::Pre(_) => Some(("```\n".to_string(), "\n```".to_string())),
-            TextEntityType::PreCode(_) => {
-                Some(("<pre><code>".to_string(), "</code></pre>".to_string()))
-            }
-            TextEntityType::Strikethrough(_) => Some(("~~".to_string(), "~~".to_string())),
-            TextEntityType::TextUrl(u) => {
-                Some(("[".to_string(), format!("]({})", u.url()).to_string()))
-            }
-            TextEntityType::Underline(_) => Some(("<u>".to_string(), "</u>".to_string())),
-            TextEntityType::Url(_) => Some(("<a>".to_string(), "</a>".to_string())),
-            TextEntityType::_Default => None,
-            // TextEntityType::BankCardNumber(_) => None,
-            TextEntityType::BotCommand(_) => None,
-            TextEntityType::Cashtag(_) => None,
-            TextEntityType::EmailAddress(_) => None,
-            TextEntityType::Mention(_) => None,
-            TextEntityType::MentionName(_) => None,
-            TextEntityType::BankCardNumber(_) => None,
-            TextEntityType::MediaTimestamp(_) => None,
-            TextEntityType::BlockQuote(_) => None,
-            TextEntityType::CustomEmoji(_) => None,
-            TextEntityType::Spoiler(_) => None,
-        };
-        if let Some((start_tag, end_tag)) = formatting {
-            stack.push((entity.offset() as usize, start_tag));
-            stack.push(((entity.offset() + entity.length()) as usize, end_tag));
-        }
-    }
-    stack.sort_by_key(|(i, _)| *i);
-    stack.reverse();
-    stack
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use rust_tdlib::types::FormattedText;
-
-    use crate::parse_formatted_text;
-
-    #[test]
-    fn test_parse_formatted_text() {
-        let tests = vec![
-            (
-                r#"{"@type": "formattedText", "text": "\uD83D\uDCB8 Налоги в Италии\n\nМы почти 3 месяца рожали этот гайд. Писали, потом переделывали заново. Брали консультации, редактировали снова и в итоге готовы отдать вам текущую обзорную версию основных налогов в Италии. Он не идеален, но уже пора выпустить и двинуться дальше.\n\nВ планах сделать еще несколько детальных гайдов. Более практичных и специализированных. Благо у нас появился человек, который активно занимается этим.\n\n\uD83D\uDD17 Гайд по налогам\n\nГайд написал совместно с нами Александр. Если у вас есть вопросы или желание что-то добавить, сотрудничать в этой области напишите ему.\n\nЖдём ваших предложений и замечаний, наша цель составить самый понятный и детальный гайд по налогам.\n\n\uD83D\uDCAC Обсудить можно в чате", "entities": [{"@type": "textEntity", "offset": 3, "length": 17, "type": {"@type": "textEntityTypeBold"}}, {"@type": "textEntity", "offset": 423, "length": 15, "type": {"@type": "textEntityTypeTextUrl", "url": "https://rutoitaly.ru/wiki/Imposte_e_tasse"}}, {"@type": "textEntity", "offset": 423, "length": 15, "type": {"@type": "textEntityTypeBold"}}, {"@type": "textEntity", "offset": 470, "length": 10, "type": {"@type": "textEntityTypeTextUrl", "url": "https://t.me/alx4039"}}, {"@type": "textEntity", "offset": 577, "length": 101, "type": {"@type": "textEntityTypeItalic"}}, {"@type": "textEntity", "offset": 678, "length": 2, "type": {"@type": "textEntityTypeCustomEmoji", "custom_emoji_id": "5443038326535759644"}}, {"@type": "textEntity", "offset": 681, "length": 21, "type": {"@type": "textEntityTypeTextUrl", "url": "https://t.me/rutoitalychat/13295/22683"}}, {"@type": "textEntity", "offset": 681, "length": 21, "type": {"@type": "textEntityTypeItalic"}}]}"#,
-                "   <b>Налоги в Италии\n\n</b>Мы почти 3 месяца рожали этот гайд. Писали, потом переделывали заново. Брали консультации, редактировали снова и в итоге готовы отдать вам текущую обзорную версию основных налогов в Италии. Он не идеален, но уже пора выпустить и двинуться дальше.\n\nВ планах сделать еще несколько детальных гайдов. Более практичных и специализированных. Благо у нас появился человек, который активно занимается этим.\n\n   <a href=\"https://rutoitaly.ru/wiki/Imposte_e_tasse\"><b>Гайд по налогам</a></b>\n\nГайд написал совместно с нами <a href=\"https://t.me/alx4039\">Александр.</a> Если у вас есть вопросы или желание что-то добавить, сотрудничать в этой области напишите ему.\n\n<i>Ждём ваших предложений и замечаний, наша цель составить самый понятный и детальный гайд по налогам.\n\n</i>   <a href=\"https://t.me/rutoitalychat/13295/22683\"><i>Обсудить можно в чате</a></i>"
-            ),
-            (
-                r#"{"@type":"formattedText","@extra":"","text":"Изображение из пятидесяти линий.\nНаткнулся на скрипт, который генерирует такие изображения вот тут.\nЛожите рядом со скриптом png изображение 750х750 в градациях серого, в исходнике меняете имя файла на ваше и запускаете исходник с помощью processing. Сгенерированное изображение будет лежать в том же каталоге.","entities":[{"@type":"textEntity","@extra":"","offset":91,"length":7,"type":{"@type":"textEntityTypeTextUrl","@extra":"","url":"https://gist.github.com/u-ndefine/8e4bc21be4275f87fefe7b2a68487161"}},{"@type":"textEntity","@extra":"","offset":239,"length":10,"type":{"@type":"textEntityTypeTextUrl","@extra":"","url":"https://processing.org/download/"}}]}"#,
-                r#"Изображение из пятидесяти линий.
-Наткнулся на скрипт, который генерирует такие изображения <a href="https://gist.github.com/u-ndefine/8e4bc21be4275f87fefe7b2a68487161">вот тут</a>.
-Ложите рядом со скриптом png изображение 750х750 в градациях серого, в исходнике меняете имя файла на ваше и запускаете исходник с помощью <a href="https://processing.org/download/">processing</a> Сгенерированное изображение будет лежать в том же каталоге."#,
-            ),
-            (
-                r#"{"@type":"formattedText","@extra":"","text":"Напоминаем, что здесь у нас есть ещё и свой чат, где проходят «публичные» интервью, а в свободное время можно просто потрещать за жизнь\n\nЗаходи, тебе здесь рады)\n\nhttps://t.me/joinchat/IqlQqUGyZpI1-0Zu8ChAmA","entities":[]}"#,
-                r#"Напоминаем, что здесь у нас есть ещё и свой чат, где проходят «публичные» интервью, а в свободное время можно просто потрещать за жизнь
-
-Заходи, тебе здесь рады)
-
-https://t.me/joinchat/IqlQqUGyZpI1-0Zu8ChAmA"#,
-            ),
-        ];
-        for (json_data, expected) in tests {
-            let formatted_text = FormattedText::from_json(json_data).expect("cannot parse json");
-            let t = parse_formatted_text(&formatted_text);
-            assert_eq!(t, expected);
+/// Resolves on SIGINT (ctrl-c) or SIGTERM (`docker stop`).
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate()).context("listen for SIGTERM")?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => {
+            res.context("listen for SIGINT")?;
+            log::info!("ctrl-c received");
         }
+        _ = terminate.recv() => {log::info!("SIGTERM received")}
     }
+    Ok(())
 }
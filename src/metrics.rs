@@ -0,0 +1,78 @@
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+/// Prometheus metrics of the process, exposed on `/metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    /// Archived messages by account and content type
+    pub processed_messages: IntCounterVec,
+    /// Bytes of downloaded attachments by account
+    pub download_bytes: IntCounterVec,
+    /// Attachments that could not be downloaded, by account
+    pub download_failures: IntCounterVec,
+    /// Duration of telegram requests by account and method
+    pub request_duration: HistogramVec,
+    /// Telegram requests retried after a flood wait or a transient error, by account and method
+    pub request_retries: IntCounterVec,
+    /// Updates waiting in the channel between TDLib and the updates reader
+    pub updates_queue_depth: IntGauge,
+    /// 1 while the updates reader is running
+    pub reader_up: IntGauge,
+}
+
+/// Metrics are global, so they can be updated from any account and any layer.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("cannot register metrics"))
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("teleforward".to_string()), None).context("create registry")?;
+        let metrics = Self {
+            processed_messages: IntCounterVec::new(
+                Opts::new("processed_messages_total", "Archived messages"),
+                &["account", "content_type"],
+            )?,
+            download_bytes: IntCounterVec::new(
+                Opts::new("download_bytes_total", "Bytes of downloaded attachments"),
+                &["account"],
+            )?,
+            download_failures: IntCounterVec::new(
+                Opts::new("download_failures_total", "Attachments that could not be downloaded"),
+                &["account"],
+            )?,
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("telegram_request_duration_seconds", "Duration of telegram requests"),
+                &["account", "method"],
+            )?,
+            request_retries: IntCounterVec::new(
+                Opts::new("telegram_request_retries_total", "Retried telegram requests"),
+                &["account", "method"],
+            )?,
+            updates_queue_depth: IntGauge::new("updates_queue_depth", "Updates waiting to be processed")?,
+            reader_up: IntGauge::new("reader_up", "Whether the updates reader is running")?,
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.processed_messages.clone()))?;
+        metrics.registry.register(Box::new(metrics.download_bytes.clone()))?;
+        metrics.registry.register(Box::new(metrics.download_failures.clone()))?;
+        metrics.registry.register(Box::new(metrics.request_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.request_retries.clone()))?;
+        metrics.registry.register(Box::new(metrics.updates_queue_depth.clone()))?;
+        metrics.registry.register(Box::new(metrics.reader_up.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("encode metrics")?;
+        String::from_utf8(buffer).context("metrics are not valid utf-8")
+    }
+}
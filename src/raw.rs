@@ -0,0 +1,144 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use rust_tdlib::types::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::config::FsyncPolicy;
+
+/// Everything needed to render an entry without network access.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawEntry {
+    pub message: Message,
+    pub channel_name: Option<String>,
+    #[serde(default)]
+    pub channel_link: Option<String>,
+    pub message_link: Option<String>,
+    /// Name of the downloaded file in the data directory
+    pub attachment: Option<String>,
+    pub reply_snippet: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Append-only JSON Lines file with raw TDLib messages, stored next to the output.
+///
+/// Follows the account's [`FsyncPolicy`] like the Markdown archive: with `Always` every append is synced.
+#[derive(Debug)]
+pub struct RawStore {
+    path: path::PathBuf,
+    fsync: FsyncPolicy,
+    lock: Mutex<()>,
+}
+
+impl RawStore {
+    pub fn new<P: Into<path::PathBuf>>(path: P, fsync: FsyncPolicy) -> Self {
+        Self {
+            path: path.into(),
+            fsync,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Default location: next to the data file, e.g. `data/data.md` -> `data/data.raw.jsonl`.
+    pub fn default_path(file_path: &str) -> path::PathBuf {
+        path::Path::new(file_path).with_extension("raw.jsonl")
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &RawEntry) -> Result<()> {
+        let _lock = self.lock.lock().expect("raw store lock poisoned");
+        let mut line = serde_json::to_string(entry).context("serialize raw entry")?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .context("open raw store")?;
+        file.write_all(line.as_bytes()).context("write raw entry")?;
+        if self.fsync == FsyncPolicy::Always {
+            file.sync_data().context("sync raw store")?;
+        }
+        Ok(())
+    }
+
+    pub fn read_all(&self) -> Result<Vec<RawEntry>> {
+        let _lock = self.lock.lock().expect("raw store lock poisoned");
+        self.read_unlocked()
+    }
+
+    /// Stored entry of the message, `None` if it was archived without storing raw messages.
+    pub fn get(&self, message_id: i64) -> Result<Option<RawEntry>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        Ok(self.read_all()?.into_iter().find(|raw| raw.message.id() == message_id))
+    }
+
+    /// Flushes the store to disk.
+    pub fn sync(&self) -> Result<()> {
+        let _lock = self.lock.lock().expect("raw store lock poisoned");
+        if !self.path.exists() {
+            return Ok(());
+        }
+        fs::File::open(&self.path)
+            .context("open raw store")?
+            .sync_all()
+            .context("sync raw store")
+    }
+
+    /// Rewrites the store, `f` may modify an entry or return `false` to drop it.
+    pub fn update<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut RawEntry) -> bool,
+    {
+        let _lock = self.lock.lock().expect("raw store lock poisoned");
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let mut content = String::new();
+        for mut entry in self.read_unlocked()? {
+            if f(&mut entry) {
+                content.push_str(&serde_json::to_string(&entry).context("serialize raw entry")?);
+                content.push('\n');
+            }
+        }
+        // the store is replaced as a whole, so a crash leaves either the old or the new version
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        let mut tmp = fs::File::create(&tmp_path).context("create temporary raw store")?;
+        tmp.write_all(content.as_bytes()).context("write raw store")?;
+        tmp.sync_all().context("sync temporary raw store")?;
+        fs::rename(&tmp_path, &self.path).context("replace raw store")?;
+        if self.fsync == FsyncPolicy::Always {
+            // persist the rename itself
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::File::open(dir)
+                    .and_then(|dir| dir.sync_all())
+                    .context("sync raw store directory")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_unlocked(&self) -> Result<Vec<RawEntry>> {
+        let file = fs::File::open(&self.path).context("open raw store")?;
+        let mut entries = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("read raw store")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => log::warn!("skip invalid raw entry at line {}: {}", i + 1, err),
+            }
+        }
+        Ok(entries)
+    }
+}
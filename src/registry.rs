@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rust_tdlib::client::tdlib_client::TdJson;
+use rust_tdlib::client::{AuthStateHandlerProxy, Worker};
+use rust_tdlib::types::Update;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::account::{supervise_account, AccountControl, Accounts};
+use crate::config::AccountSettings;
+use crate::dry_run::DryRunReport;
+use crate::error::AppError;
+use crate::logging::mask_phone;
+use crate::telegram::record::Recorder;
+
+/// Accounts supervised by `run`, which can be added and removed while others keep running,
+/// e.g. on config reload or through the admin API.
+///
+/// The registry is the authoritative set of accounts: [`Accounts`] only mirrors their running clients and states,
+/// so accounts are added and removed here, never in [`Accounts`] directly.
+///
+/// Changes are applied one at a time: removing an account waits until its output is flushed and its client is closed,
+/// so it can be added again with the same database right away.
+pub struct AccountRegistry {
+    worker: Arc<Mutex<Worker<AuthStateHandlerProxy, TdJson>>>,
+    api_id: i32,
+    api_hash: String,
+    sender: Sender<Box<Update>>,
+    accounts: Arc<Accounts>,
+    recorder: Option<Arc<Recorder>>,
+    dry_run: Option<Arc<DryRunReport>>,
+    supervisors: Mutex<HashMap<String, Supervisor>>,
+}
+
+struct Supervisor {
+    settings: AccountSettings,
+    control: Arc<AccountControl>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl AccountRegistry {
+    pub fn new(
+        worker: Arc<Mutex<Worker<AuthStateHandlerProxy, TdJson>>>,
+        api_id: i32,
+        api_hash: String,
+        sender: Sender<Box<Update>>,
+        accounts: Arc<Accounts>,
+        recorder: Option<Arc<Recorder>>,
+        dry_run: Option<Arc<DryRunReport>>,
+    ) -> Self {
+        Self {
+            worker,
+            api_id,
+            api_hash,
+            sender,
+            accounts,
+            recorder,
+            dry_run,
+            supervisors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Running clients and states of registered accounts.
+    pub fn accounts(&self) -> &Arc<Accounts> {
+        &self.accounts
+    }
+
+    /// Phones of registered accounts, sorted.
+    pub async fn phones(&self) -> Vec<String> {
+        let mut phones = self.supervisors.lock().await.keys().cloned().collect::<Vec<_>>();
+        phones.sort();
+        phones
+    }
+
+    /// Settings the account was added with.
+    pub async fn settings(&self, phone: &str) -> Option<AccountSettings> {
+        self.supervisors.lock().await.get(phone).map(|s| s.settings.clone())
+    }
+
+    /// Controls of the account, e.g. to submit an auth code.
+    pub async fn control(&self, phone: &str) -> Option<Arc<AccountControl>> {
+        self.supervisors.lock().await.get(phone).map(|s| s.control.clone())
+    }
+
+    /// Starts supervising the account. Fails if the phone or the database directory is already in use.
+    pub async fn add(&self, account: AccountSettings) -> Result<(), AppError> {
+        let mut supervisors = self.supervisors.lock().await;
+        if supervisors.contains_key(&account.phone) {
+            return Err(AppError::AccountExists(mask_phone(&account.phone)));
+        }
+        let tddb_dir = path::Path::new(&account.tddb_dir);
+        if let Some(other) = supervisors
+            .values()
+            .find(|s| path::Path::new(&s.settings.tddb_dir).components().eq(tddb_dir.components()))
+        {
+            return Err(AppError::AccountExists(format!(
+                "{} uses {}",
+                mask_phone(&other.settings.phone),
+                account.tddb_dir
+            )));
+        }
+
+        let control = Arc::new(AccountControl::default());
+        let (stop, stopped) = watch::channel(false);
+        let task = tokio::spawn(supervise_account(
+            self.worker.clone(),
+            account.clone(),
+            self.api_id,
+            self.api_hash.clone(),
+            self.sender.clone(),
+            self.accounts.clone(),
+            self.recorder.clone(),
+            self.dry_run.clone(),
+            control.clone(),
+            stopped,
+        ));
+        log::info!("{} added", mask_phone(&account.phone));
+        supervisors.insert(
+            account.phone.clone(),
+            Supervisor {
+                settings: account,
+                control,
+                stop,
+                task,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stops the account and waits until its output is flushed and its client is closed.
+    pub async fn remove(&self, phone: &str) -> Result<(), AppError> {
+        let mut supervisors = self.supervisors.lock().await;
+        let supervisor = supervisors
+            .remove(phone)
+            .ok_or_else(|| AppError::AccountNotFound(mask_phone(phone)))?;
+        stop(phone, supervisor).await;
+        self.accounts.remove_state(phone);
+        log::info!("{} removed", mask_phone(phone));
+        Ok(())
+    }
+
+    /// Stops all accounts, on shutdown.
+    pub async fn stop_all(&self) {
+        let mut supervisors = self.supervisors.lock().await;
+        for (phone, supervisor) in supervisors.drain() {
+            stop(&phone, supervisor).await;
+        }
+    }
+}
+
+async fn stop(phone: &str, supervisor: Supervisor) {
+    // the supervisor may have already given up, e.g. waiting for an auth code
+    let _ = supervisor.stop.send(true);
+    if let Err(err) = supervisor.task.await {
+        log::error!("{} supervisor panicked: {}", mask_phone(phone), err);
+    }
+}
@@ -0,0 +1,198 @@
+use std::path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use log::LevelFilter;
+
+use crate::config::{AccountSettings, Config};
+use crate::error::AppError;
+use crate::logging::{self, mask_phone};
+use crate::registry::AccountRegistry;
+
+/// Loads the config again and applies it to running accounts: stops removed accounts, starts added ones and restarts
+/// changed ones, and changes the log level. Nothing is applied if the new config is invalid, and previous accounts are
+/// restored if one of them cannot be started.
+///
+/// `telegram`, `http`, `admin` and `log_format` are only read on start, their changes are reported and ignored.
+pub async fn reload(path: &path::Path, current: &Config, registry: &AccountRegistry) -> Result<Config> {
+    let config = Config::load(path)?;
+    let problems = config.check();
+    if !problems.is_empty() {
+        bail!(
+            "{}",
+            problems.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+        );
+    }
+    let level = LevelFilter::from_str(&config.log_level).context("parse loglevel")?;
+
+    if config.telegram != current.telegram {
+        log::warn!("telegram settings changed, restart to apply them");
+    }
+    if config.http != current.http || config.admin != current.admin {
+        log::warn!("http or admin settings changed, restart to apply them");
+    }
+    if config.log_format != current.log_format {
+        log::warn!("log_format changed, restart to apply it");
+    }
+
+    apply_accounts(&config.accounts, registry).await?;
+    logging::set_level(level);
+    log::info!("config reloaded");
+    Ok(config)
+}
+
+/// Accounts [`reload`] is applied to, implemented by [`AccountRegistry`].
+#[async_trait]
+trait RunningAccounts: Sync {
+    async fn phones(&self) -> Vec<String>;
+    async fn settings(&self, phone: &str) -> Option<AccountSettings>;
+    async fn add(&self, account: AccountSettings) -> Result<(), AppError>;
+    async fn remove(&self, phone: &str) -> Result<(), AppError>;
+}
+
+#[async_trait]
+impl RunningAccounts for AccountRegistry {
+    async fn phones(&self) -> Vec<String> {
+        AccountRegistry::phones(self).await
+    }
+
+    async fn settings(&self, phone: &str) -> Option<AccountSettings> {
+        AccountRegistry::settings(self, phone).await
+    }
+
+    async fn add(&self, account: AccountSettings) -> Result<(), AppError> {
+        AccountRegistry::add(self, account).await
+    }
+
+    async fn remove(&self, phone: &str) -> Result<(), AppError> {
+        AccountRegistry::remove(self, phone).await
+    }
+}
+
+/// Stops removed and changed accounts and starts added and changed ones. If any of it fails, accounts started so far
+/// are stopped and the stopped ones are started again with their previous settings.
+async fn apply_accounts(accounts: &[AccountSettings], registry: &dyn RunningAccounts) -> Result<()> {
+    // removed and changed accounts are stopped first, so their database directories can be taken by others
+    let mut stopped = Vec::new();
+    for phone in registry.phones().await {
+        let Some(running) = registry.settings(&phone).await else {
+            continue;
+        };
+        match accounts.iter().find(|account| account.phone == phone) {
+            Some(account) if account == &running => continue,
+            Some(_) => log::info!("{} settings changed, restarting", mask_phone(&phone)),
+            None => log::info!("{} removed from config, stopping", mask_phone(&phone)),
+        }
+        if let Err(err) = registry.remove(&phone).await {
+            roll_back(registry, &[], stopped).await;
+            return Err(err).context(format!("stop {}", mask_phone(&phone)));
+        }
+        stopped.push(running);
+    }
+    let mut started = Vec::new();
+    for account in accounts.iter() {
+        if registry.settings(&account.phone).await.is_some() {
+            continue;
+        }
+        if let Err(err) = registry.add(account.clone()).await {
+            roll_back(registry, &started, stopped).await;
+            return Err(err).context(format!("start {}", mask_phone(&account.phone)));
+        }
+        started.push(account.phone.clone());
+    }
+    Ok(())
+}
+
+async fn roll_back(registry: &dyn RunningAccounts, started: &[String], stopped: Vec<AccountSettings>) {
+    log::warn!("reload failed, restoring previous accounts");
+    for phone in started.iter() {
+        if let Err(err) = registry.remove(phone).await {
+            log::error!("cannot stop {}: {}", mask_phone(phone), err);
+        }
+    }
+    for account in stopped {
+        let phone = account.phone.clone();
+        if let Err(err) = registry.add(account).await {
+            log::error!("cannot restart {}: {}", mask_phone(&phone), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+    use tokio::sync::Mutex;
+
+    use super::{apply_accounts, RunningAccounts};
+    use crate::config::AccountSettings;
+    use crate::error::AppError;
+
+    /// Registry whose `add` fails for one phone.
+    #[derive(Default)]
+    struct FakeRegistry {
+        accounts: Mutex<HashMap<String, AccountSettings>>,
+        failing_phone: String,
+    }
+
+    #[async_trait]
+    impl RunningAccounts for FakeRegistry {
+        async fn phones(&self) -> Vec<String> {
+            let mut phones = self.accounts.lock().await.keys().cloned().collect::<Vec<_>>();
+            phones.sort();
+            phones
+        }
+
+        async fn settings(&self, phone: &str) -> Option<AccountSettings> {
+            self.accounts.lock().await.get(phone).cloned()
+        }
+
+        async fn add(&self, account: AccountSettings) -> Result<(), AppError> {
+            if account.phone == self.failing_phone {
+                return Err(AppError::AccountExists(account.phone));
+            }
+            self.accounts.lock().await.insert(account.phone.clone(), account);
+            Ok(())
+        }
+
+        async fn remove(&self, phone: &str) -> Result<(), AppError> {
+            match self.accounts.lock().await.remove(phone) {
+                Some(_) => Ok(()),
+                None => Err(AppError::AccountNotFound(phone.to_string())),
+            }
+        }
+    }
+
+    fn account(phone: &str, file_path: &str) -> AccountSettings {
+        AccountSettings {
+            phone: phone.to_string(),
+            tddb_dir: format!("tddb/{}", phone),
+            file_path: file_path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_accounts() {
+        let registry = FakeRegistry {
+            failing_phone: "+79990000003".to_string(),
+            ..Default::default()
+        };
+        for account in [account("+79990000001", "a.md"), account("+79990000002", "b.md")] {
+            registry.add(account).await.unwrap();
+        }
+        let previous = registry.accounts.lock().await.clone();
+
+        // the first account changed, the second one is removed and the third one fails to start: nothing changes
+        let accounts = [account("+79990000001", "changed.md"), account("+79990000003", "c.md")];
+        let err = apply_accounts(&accounts, &registry).await.unwrap_err();
+        assert_eq!(format!("{:#}", err), "start +79*******03: account already exists: +79990000003");
+        assert_eq!(*registry.accounts.lock().await, previous);
+
+        apply_accounts(&accounts[..1], &registry).await.unwrap();
+        assert_eq!(registry.phones().await, vec!["+79990000001"]);
+        assert_eq!(registry.settings("+79990000001").await.unwrap().file_path, "changed.md");
+    }
+}
@@ -0,0 +1,529 @@
+use std::{fs, path};
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDateTime;
+use rust_tdlib::types::{FormattedText, Message, MessageContent, MessageOrigin, MessageReplyTo, TextEntity, TextEntityType};
+
+use crate::config::Config;
+use crate::index::DATE_FORMAT;
+use crate::logging::mask_phone;
+use crate::raw::{RawEntry, RawStore};
+use crate::sink::archived_ids;
+
+pub fn entry_anchor(message_id: i64, content_type: &str, origin: Option<&str>) -> String {
+    match origin {
+        Some(origin) => format!(
+            r#"<a id="msg-{}" data-type="{}" data-origin="{}"></a>"#,
+            message_id, content_type, origin
+        ),
+        None => format!(r#"<a id="msg-{}" data-type="{}"></a>"#, message_id, content_type),
+    }
+}
+
+/// Kind of the forward origin, `None` if the message is not forwarded.
+pub fn origin_kind(message: &Message) -> Option<&'static str> {
+    message.forward_info().as_ref().map(|forward_info| match forward_info.origin() {
+        MessageOrigin::_Default => "unknown",
+        MessageOrigin::Channel(_) => "channel",
+        MessageOrigin::Chat(_) => "chat",
+        MessageOrigin::HiddenUser(_) => "hidden_user",
+        MessageOrigin::User(_) => "user",
+    })
+}
+
+pub fn content_type_name(content: &MessageContent) -> &'static str {
+    match content {
+        MessageContent::MessageText(_) => "text",
+        MessageContent::MessageAnimation(_) => "animation",
+        MessageContent::MessageAudio(_) => "audio",
+        MessageContent::MessageDocument(_) => "document",
+        MessageContent::MessagePhoto(_) => "photo",
+        MessageContent::MessageVideo(_) => "video",
+        MessageContent::MessageVoiceNote(_) => "voice_note",
+        _ => "other",
+    }
+}
+
+/// Renders a complete Markdown entry, `body` is the rendered message content.
+pub fn render_entry(raw: &RawEntry, body: &str) -> Result<String> {
+    let message = &raw.message;
+    let message_date = NaiveDateTime::from_timestamp_opt(message.date() as i64, 0)
+        .context("cannot parse message date")?;
+    let context = render_message_context(message, raw.reply_snippet.as_deref());
+    let mut text = match &raw.message_link {
+        Some(link) => {
+            format!(
+                r#"
+**Date:** [{message_date}]({message_link})
+
+{context}{text}
+
+---
+
+"#,
+                message_date = message_date.format(DATE_FORMAT),
+                message_link = link,
+                context = context,
+                text = body
+            )
+        },
+        None => {
+            format!(
+                r#"
+**Date:** {message_date}
+
+{context}{text}
+
+---
+
+"#,
+                message_date = message_date.format(DATE_FORMAT),
+                context = context,
+                text = body
+            )
+        }
+    };
+    if let Some(n) = &raw.channel_name {
+        text = match &raw.channel_link {
+            Some(link) => format!("**From:** [{}]({})\n\n{}", n, link, text),
+            None => format!("**From:** {}\n\n{}", n, text),
+        };
+    }
+    if !raw.tags.is_empty() {
+        text = format!("{}\n\n{}", tags_line(&raw.tags), text);
+    }
+    let anchor = entry_anchor(message.id(), content_type_name(message.content()), origin_kind(message));
+    Ok(format!("{}\n{}", anchor, text))
+}
+
+/// Renders stored raw messages in the order they were archived.
+pub fn render_raw_entries(entries: &[RawEntry]) -> Result<String> {
+    let mut output = String::new();
+    for raw in entries.iter() {
+        if let Some(body) = parse_message_content(raw.message.content(), raw.attachment.as_deref()) {
+            output.push_str(&render_entry(raw, &body).context(format!("render message {}", raw.message.id()))?);
+        }
+    }
+    Ok(output)
+}
+
+/// Ids of entries archived in `markdown` but missing from the raw store, e.g. archived before `store_raw`
+/// was enabled. Rendering over the file would drop them.
+pub fn missing_raw_entries(markdown: &str, entries: &[RawEntry]) -> Vec<i64> {
+    let stored = entries.iter().map(|raw| raw.message.id()).collect::<std::collections::HashSet<_>>();
+    let mut missing = archived_ids(markdown)
+        .into_iter()
+        .filter(|id| !stored.contains(id))
+        .collect::<Vec<_>>();
+    missing.sort_unstable();
+    missing
+}
+
+/// Regenerates archives of the configured accounts, or only of the one with `phone`, from their raw stores.
+///
+/// Output goes to `output`, to `data.rendered.md` next to account's `file_path` by default, or replaces
+/// `file_path` itself with `in_place`, which is refused if the file has entries missing from the raw store.
+pub fn render_accounts(config: &Config, phone: Option<&str>, output: Option<&str>, in_place: bool) -> Result<()> {
+    for account in config.accounts.iter() {
+        if phone.is_some_and(|phone| phone != account.phone) {
+            continue;
+        }
+        let raw_store = RawStore::new(RawStore::default_path(&account.file_path), account.fsync);
+        if !raw_store.path().exists() {
+            log::warn!(
+                "no raw messages stored for {}, enable `store_raw` to collect them",
+                mask_phone(&account.phone)
+            );
+            continue;
+        }
+        let entries = raw_store.read_all().context(format!("read raw messages of {}", mask_phone(&account.phone)))?;
+        let rendered = render_raw_entries(&entries)?;
+        let output_path = if in_place {
+            let current = match fs::read_to_string(&account.file_path) {
+                Ok(content) => content,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err).context(format!("read {}", &account.file_path)),
+            };
+            let missing = missing_raw_entries(&current, &entries);
+            if !missing.is_empty() {
+                bail!(
+                    "{} has {} entries missing from the raw store (first: {}), rendering in place would drop them, \
+                     use --output instead",
+                    account.file_path,
+                    missing.len(),
+                    missing[0]
+                );
+            }
+            account.file_path.clone()
+        } else {
+            output.map(str::to_string).unwrap_or_else(|| {
+                path::Path::new(&account.file_path)
+                    .with_extension("rendered.md")
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        };
+        let tmp_path = format!("{}.tmp", output_path);
+        fs::write(&tmp_path, rendered).context("write rendered output")?;
+        fs::rename(&tmp_path, &output_path).context("replace output")?;
+        log::info!("rendered {} entries of {} to {}", entries.len(), mask_phone(&account.phone), output_path);
+    }
+    Ok(())
+}
+
+pub fn tags_line(tags: &[String]) -> String {
+    format!(
+        "**Tags:** {}",
+        tags.iter().map(|t| format!("#{}", t)).collect::<Vec<_>>().join(" ")
+    )
+}
+
+/// Renders reply and thread context lines of an entry, empty if the message has none.
+pub fn render_message_context(message: &Message, reply_snippet: Option<&str>) -> String {
+    let mut context = String::new();
+    if message.message_thread_id() != 0 {
+        let kind = if message.is_topic_message() { "Topic" } else { "Thread" };
+        context.push_str(&format!("**{}:** {}\n\n", kind, message.message_thread_id()));
+    }
+    let reply = match message.reply_to() {
+        Some(MessageReplyTo::Message(reply)) => reply,
+        _ => return context,
+    };
+    if reply.chat_id() == message.chat_id() {
+        context.push_str(&format!(
+            "**In reply to:** [message {id}](#msg-{id})\n",
+            id = reply.message_id()
+        ));
+    } else {
+        context.push_str(&format!(
+            "**In reply to:** message {} in chat {}\n",
+            reply.message_id(),
+            reply.chat_id()
+        ));
+    }
+    if let Some(snippet) = reply_snippet {
+        context.push_str(&format!("> {}\n", snippet));
+    }
+    context.push('\n');
+    context
+}
+
+
+/// Returns text of a text message or caption of a media message.
+pub fn message_formatted_text(content: &MessageContent) -> Option<&FormattedText> {
+    match content {
+        MessageContent::MessageText(text) => Some(text.text()),
+        MessageContent::MessageAnimation(animation) => Some(animation.caption()),
+        MessageContent::MessageAudio(audio) => Some(audio.caption()),
+        MessageContent::MessageDocument(document) => Some(document.caption()),
+        MessageContent::MessagePhoto(photo) => Some(photo.caption()),
+        MessageContent::MessageVideo(video) => Some(video.caption()),
+        MessageContent::MessageVoiceNote(voice_note) => Some(voice_note.caption()),
+        _ => None,
+    }
+}
+
+pub fn make_snippet(text: &str) -> String {
+    const SNIPPET_LENGTH: usize = 100;
+    let mut snippet = text
+        .chars()
+        .take(SNIPPET_LENGTH)
+        .map(|c| if c == '\n' { ' ' } else { c })
+        .collect::<String>();
+    if text.chars().count() > SNIPPET_LENGTH {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Renders message content, `attachment` is a name of the downloaded file in the data directory.
+pub fn parse_message_content(content: &MessageContent, attachment: Option<&str>) -> Option<String> {
+    match content {
+        MessageContent::MessageText(text) => return Some(parse_formatted_text(text.text())),
+        MessageContent::MessageAnimation(message_animation) => {
+            return Some(parse_formatted_text(message_animation.caption()));
+        }
+        MessageContent::MessageAudio(message_audio) => {
+            return Some(parse_formatted_text(message_audio.caption()));
+        }
+        MessageContent::MessageDocument(message_document) => {
+            let mut parsed_text = parse_formatted_text(message_document.caption());
+            if let Some(file_name) = attachment {
+                parsed_text.push_str(format!("\n\n![]({})", file_name).as_str());
+            }
+            return Some(parsed_text);
+        }
+        MessageContent::MessagePhoto(photo) => {
+            return Some(match attachment {
+                Some(file_name) => {
+                    let mut parsed_text = format!("\n\n![]({})\n\n", file_name);
+                    parsed_text.push_str(parse_formatted_text(photo.caption()).as_str());
+                    parsed_text
+                }
+                None => parse_formatted_text(photo.caption()),
+            });
+        }
+        MessageContent::MessageVideo(message_video) => {
+            return Some(parse_formatted_text(message_video.caption()));
+        }
+
+        // probably needs to be supported
+        MessageContent::MessageLocation(_) => {}
+        MessageContent::MessageVideoNote(_) => {}
+        MessageContent::MessageVoiceNote(_) => {}
+
+        MessageContent::_Default => {}
+        MessageContent::MessageAnimatedEmoji(_) => {}
+        MessageContent::MessageBasicGroupChatCreate(_) => {}
+        MessageContent::MessageCall(_) => {}
+        MessageContent::MessageChatAddMembers(_) => {}
+        MessageContent::MessageChatChangePhoto(_) => {}
+        MessageContent::MessageChatChangeTitle(_) => {}
+        MessageContent::MessageChatDeleteMember(_) => {}
+        MessageContent::MessageChatDeletePhoto(_) => {}
+        MessageContent::MessageChatJoinByLink(_) => {}
+        MessageContent::MessageChatJoinByRequest(_) => {}
+        MessageContent::MessageChatSetTheme(_) => {}
+        MessageContent::MessageChatUpgradeFrom(_) => {}
+        MessageContent::MessageChatUpgradeTo(_) => {}
+        MessageContent::MessageContact(_) => {}
+        MessageContent::MessageContactRegistered(_) => {}
+        MessageContent::MessageCustomServiceAction(_) => {}
+        MessageContent::MessageDice(_) => {}
+        MessageContent::MessageExpiredPhoto(_) => {}
+        MessageContent::MessageExpiredVideo(_) => {}
+        MessageContent::MessageGame(_) => {}
+        MessageContent::MessageGameScore(_) => {}
+        MessageContent::MessageInviteVideoChatParticipants(_) => {}
+        MessageContent::MessageInvoice(_) => {}
+        MessageContent::MessagePassportDataReceived(_) => {}
+        MessageContent::MessagePassportDataSent(_) => {}
+        MessageContent::MessagePaymentSuccessful(_) => {}
+        MessageContent::MessagePaymentSuccessfulBot(_) => {}
+        MessageContent::MessagePinMessage(_) => {}
+        MessageContent::MessagePoll(_) => {}
+        MessageContent::MessageProximityAlertTriggered(_) => {}
+        MessageContent::MessageScreenshotTaken(_) => {}
+        MessageContent::MessageSticker(_) => {}
+        MessageContent::MessageSupergroupChatCreate(_) => {}
+        MessageContent::MessageUnsupported(_) => {}
+        MessageContent::MessageVenue(_) => {}
+        MessageContent::MessageVideoChatEnded(_) => {}
+        MessageContent::MessageVideoChatScheduled(_) => {}
+        MessageContent::MessageVideoChatStarted(_) => {}
+        MessageContent::MessageBotWriteAccessAllowed(_) => {}
+        MessageContent::MessageChatSetBackground(_) => {}
+        MessageContent::MessageChatSetMessageAutoDeleteTime(_) => {}
+        MessageContent::MessageChatShared(_) => {}
+        MessageContent::MessageForumTopicCreated(_) => {}
+        MessageContent::MessageForumTopicEdited(_) => {}
+        MessageContent::MessageForumTopicIsClosedToggled(_) => {}
+        MessageContent::MessageForumTopicIsHiddenToggled(_) => {}
+        MessageContent::MessageGiftedPremium(_) => {}
+        MessageContent::MessagePremiumGiftCode(_) => {}
+        MessageContent::MessagePremiumGiveaway(_) => {}
+        MessageContent::MessagePremiumGiveawayCreated(_) => {}
+        MessageContent::MessageStory(_) => {}
+        MessageContent::MessageSuggestProfilePhoto(_) => {}
+        MessageContent::MessageUserShared(_) => {}
+        MessageContent::MessageWebAppDataReceived(_) => {}
+        MessageContent::MessageWebAppDataSent(_) => {}
+    }
+    None
+}
+
+pub fn parse_formatted_text(formatted_text: &FormattedText) -> String {
+    let mut entities_by_index = make_entities_stack(formatted_text.entities());
+    let mut result_text = String::new();
+    let mut current_entity = match entities_by_index.pop() {
+        None => return formatted_text.text().clone(),
+        Some(entity) => entity,
+    };
+    for (i, ch) in formatted_text.text().encode_utf16().enumerate() {
+        let c = char::from_u32(ch as u32).unwrap_or(' ').to_string();
+        if i == current_entity.0 {
+            result_text.push_str(current_entity.1.as_str());
+            current_entity = loop {
+                match entities_by_index.pop() {
+                    None => {
+                        result_text = format!(
+                            "{}{}",
+                            result_text,
+                            &formatted_text
+                                .text()
+                                .chars()
+                                .skip(i + 1)
+                                .take(formatted_text.text().len() - i)
+                                .collect::<String>()
+                        );
+                        return result_text;
+                    }
+                    Some(entity) => {
+                        if entity.0 == i {
+                            result_text.push_str(&entity.1);
+                        } else {
+                            result_text.push_str(c.as_str());
+                            break entity;
+                        }
+                    }
+                }
+            }
+        } else {
+            result_text.push_str(c.as_str());
+        }
+    }
+
+    result_text.push_str(&current_entity.1);
+
+    loop {
+        match entities_by_index.pop() {
+            None => return result_text,
+            Some(entity) => {
+                result_text.push_str(&entity.1);
+            }
+        }
+    }
+}
+
+fn make_entities_stack(entities: &[TextEntity]) -> Vec<(usize, String)> {
+    let mut stack = Vec::new();
+    for entity in entities {
+        let formatting = match entity.type_() {
+            TextEntityType::Bold(_) => Some(("**".to_string(), "**".to_string())),
+            TextEntityType::Code(_) => Some(("`".to_string(), "`".to_string())),
+            TextEntityType::Hashtag(_) => Some(("#".to_string(), "".to_string())),
+            TextEntityType::Italic(_) => Some(("<i>".to_string(), "</i>".to_string())),
+            TextEntityType::PhoneNumber(_) => Some(("<phone>".to_string(), "</phone>".to_string())),
+            TextEntityType::Pre(_) => Some(("```\n".to_string(), "\n```".to_string())),
+            TextEntityType::PreCode(_) => {
+                Some(("<pre><code>".to_string(), "</code></pre>".to_string()))
+            }
+            TextEntityType::Strikethrough(_) => Some(("~~".to_string(), "~~".to_string())),
+            TextEntityType::TextUrl(u) => {
+                Some(("[".to_string(), format!("]({})", u.url()).to_string()))
+            }
+            TextEntityType::Underline(_) => Some(("<u>".to_string(), "</u>".to_string())),
+            TextEntityType::Url(_) => Some(("<a>".to_string(), "</a>".to_string())),
+            TextEntityType::_Default => None,
+            // TextEntityType::BankCardNumber(_) => None,
+            TextEntityType::BotCommand(_) => None,
+            TextEntityType::Cashtag(_) => None,
+            TextEntityType::EmailAddress(_) => None,
+            TextEntityType::Mention(_) => None,
+            TextEntityType::MentionName(_) => None,
+            TextEntityType::BankCardNumber(_) => None,
+            TextEntityType::MediaTimestamp(_) => None,
+            TextEntityType::BlockQuote(_) => None,
+            TextEntityType::CustomEmoji(_) => None,
+            TextEntityType::Spoiler(_) => None,
+        };
+        if let Some((start_tag, end_tag)) = formatting {
+            stack.push((entity.offset() as usize, start_tag));
+            stack.push(((entity.offset() + entity.length()) as usize, end_tag));
+        }
+    }
+    stack.sort_by_key(|(i, _)| *i);
+    stack.reverse();
+    stack
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_tdlib::types::{FormattedText, Message};
+
+    use super::{
+        make_snippet, missing_raw_entries, origin_kind, parse_formatted_text, render_message_context,
+        render_raw_entries,
+    };
+    use crate::raw::RawEntry;
+
+    #[test]
+    fn test_parse_formatted_text() {
+        let tests = vec![
+            (
+                r#"{"@type": "formattedText", "text": "\uD83D\uDCB8 Налоги в Италии\n\nМы почти 3 месяца рожали этот гайд. Писали, потом переделывали заново. Брали консультации, редактировали снова и в итоге готовы отдать вам текущую обзорную версию основных налогов в Италии. Он не идеален, но уже пора выпустить и двинуться дальше.\n\nВ планах сделать еще несколько детальных гайдов. Более практичных и специализированных. Благо у нас появился человек, который активно занимается этим.\n\n\uD83D\uDD17 Гайд по налогам\n\nГайд написал совместно с нами Александр. Если у вас есть вопросы или желание что-то добавить, сотрудничать в этой области напишите ему.\n\nЖдём ваших предложений и замечаний, наша цель составить самый понятный и детальный гайд по налогам.\n\n\uD83D\uDCAC Обсудить можно в чате", "entities": [{"@type": "textEntity", "offset": 3, "length": 17, "type": {"@type": "textEntityTypeBold"}}, {"@type": "textEntity", "offset": 423, "length": 15, "type": {"@type": "textEntityTypeTextUrl", "url": "https://rutoitaly.ru/wiki/Imposte_e_tasse"}}, {"@type": "textEntity", "offset": 423, "length": 15, "type": {"@type": "textEntityTypeBold"}}, {"@type": "textEntity", "offset": 470, "length": 10, "type": {"@type": "textEntityTypeTextUrl", "url": "https://t.me/alx4039"}}, {"@type": "textEntity", "offset": 577, "length": 101, "type": {"@type": "textEntityTypeItalic"}}, {"@type": "textEntity", "offset": 678, "length": 2, "type": {"@type": "textEntityTypeCustomEmoji", "custom_emoji_id": "5443038326535759644"}}, {"@type": "textEntity", "offset": 681, "length": 21, "type": {"@type": "textEntityTypeTextUrl", "url": "https://t.me/rutoitalychat/13295/22683"}}, {"@type": "textEntity", "offset": 681, "length": 21, "type": {"@type": "textEntityTypeItalic"}}]}"#,
+                "   <b>Налоги в Италии\n\n</b>Мы почти 3 месяца рожали этот гайд. Писали, потом переделывали заново. Брали консультации, редактировали снова и в итоге готовы отдать вам текущую обзорную версию основных налогов в Италии. Он не идеален, но уже пора выпустить и двинуться дальше.\n\nВ планах сделать еще несколько детальных гайдов. Более практичных и специализированных. Благо у нас появился человек, который активно занимается этим.\n\n   <a href=\"https://rutoitaly.ru/wiki/Imposte_e_tasse\"><b>Гайд по налогам</a></b>\n\nГайд написал совместно с нами <a href=\"https://t.me/alx4039\">Александр.</a> Если у вас есть вопросы или желание что-то добавить, сотрудничать в этой области напишите ему.\n\n<i>Ждём ваших предложений и замечаний, наша цель составить самый понятный и детальный гайд по налогам.\n\n</i>   <a href=\"https://t.me/rutoitalychat/13295/22683\"><i>Обсудить можно в чате</a></i>"
+            ),
+            (
+                r#"{"@type":"formattedText","@extra":"","text":"Изображение из пятидесяти линий.\nНаткнулся на скрипт, который генерирует такие изображения вот тут.\nЛожите рядом со скриптом png изображение 750х750 в градациях серого, в исходнике меняете имя файла на ваше и запускаете исходник с помощью processing. Сгенерированное изображение будет лежать в том же каталоге.","entities":[{"@type":"textEntity","@extra":"","offset":91,"length":7,"type":{"@type":"textEntityTypeTextUrl","@extra":"","url":"https://gist.github.com/u-ndefine/8e4bc21be4275f87fefe7b2a68487161"}},{"@type":"textEntity","@extra":"","offset":239,"length":10,"type":{"@type":"textEntityTypeTextUrl","@extra":"","url":"https://processing.org/download/"}}]}"#,
+                r#"Изображение из пятидесяти линий.
+Наткнулся на скрипт, который генерирует такие изображения <a href="https://gist.github.com/u-ndefine/8e4bc21be4275f87fefe7b2a68487161">вот тут</a>.
+Ложите рядом со скриптом png изображение 750х750 в градациях серого, в исходнике меняете имя файла на ваше и запускаете исходник с помощью <a href="https://processing.org/download/">processing</a> Сгенерированное изображение будет лежать в том же каталоге."#,
+            ),
+            (
+                r#"{"@type":"formattedText","@extra":"","text":"Напоминаем, что здесь у нас есть ещё и свой чат, где проходят «публичные» интервью, а в свободное время можно просто потрещать за жизнь\n\nЗаходи, тебе здесь рады)\n\nhttps://t.me/joinchat/IqlQqUGyZpI1-0Zu8ChAmA","entities":[]}"#,
+                r#"Напоминаем, что здесь у нас есть ещё и свой чат, где проходят «публичные» интервью, а в свободное время можно просто потрещать за жизнь
+
+Заходи, тебе здесь рады)
+
+https://t.me/joinchat/IqlQqUGyZpI1-0Zu8ChAmA"#,
+            ),
+        ];
+        for (json_data, expected) in tests {
+            let formatted_text = FormattedText::from_json(json_data).expect("cannot parse json");
+            let t = parse_formatted_text(&formatted_text);
+            assert_eq!(t, expected);
+        }
+    }
+
+    #[test]
+    fn test_make_snippet() {
+        assert_eq!(make_snippet("short\nline"), "short line");
+        // cut by characters, not bytes: each of these takes 2 or 4 bytes
+        let cyrillic = "я".repeat(150);
+        assert_eq!(make_snippet(&cyrillic), format!("{}…", "я".repeat(100)));
+        let emoji = format!("{}🦀🦀", "a".repeat(99));
+        assert_eq!(make_snippet(&emoji), format!("{}🦀…", "a".repeat(99)));
+        assert_eq!(make_snippet(&"ы".repeat(100)), "ы".repeat(100));
+    }
+
+    #[test]
+    fn test_render_message_context() {
+        let reply = |reply_chat_id: i64| {
+            Message::from_json(&format!(
+                r#"{{"@type":"message","id":11,"chat_id":100,"date":1700000000,"content":{{"@type":"messageText","text":{{"@type":"formattedText","text":"answer","entities":[]}}}},"reply_to":{{"@type":"messageReplyToMessage","chat_id":{},"message_id":10}}}}"#,
+                reply_chat_id
+            ))
+            .expect("cannot parse json")
+        };
+        assert_eq!(
+            render_message_context(&reply(100), Some("hello world")),
+            "**In reply to:** [message 10](#msg-10)\n> hello world\n\n"
+        );
+        // the replied message was deleted, so there is no snippet
+        assert_eq!(render_message_context(&reply(100), None), "**In reply to:** [message 10](#msg-10)\n\n");
+        assert_eq!(render_message_context(&reply(5), None), "**In reply to:** message 10 in chat 5\n\n");
+
+        // forwarded from an origin TDLib didn't report
+        let forwarded = Message::from_json(
+            r#"{"@type":"message","id":12,"chat_id":100,"date":1700000000,"forward_info":{"@type":"messageForwardInfo","date":1700000000},"content":{"@type":"messageText","text":{"@type":"formattedText","text":"forwarded","entities":[]}}}"#,
+        )
+        .expect("cannot parse json");
+        assert_eq!(render_message_context(&forwarded, None), "");
+        assert_eq!(origin_kind(&forwarded), Some("unknown"));
+    }
+
+    #[test]
+    fn test_missing_raw_entries() {
+        let raw = |id: i64| RawEntry {
+            message: Message::from_json(&format!(
+                r#"{{"@type":"message","id":{},"chat_id":100,"date":1700000000,"content":{{"@type":"messageText","text":{{"@type":"formattedText","text":"text {}","entities":[]}}}}}}"#,
+                id, id
+            ))
+            .expect("cannot parse json"),
+            channel_name: None,
+            channel_link: None,
+            message_link: None,
+            attachment: None,
+            reply_snippet: None,
+            tags: vec![],
+        };
+        let stored = vec![raw(2), raw(3)];
+        let rendered = render_raw_entries(&stored).expect("cannot render");
+        assert!(missing_raw_entries(&rendered, &stored).is_empty());
+
+        // entry 1 was archived before raw messages were stored
+        let markdown = format!("{}{}", render_raw_entries(&[raw(1)]).expect("cannot render"), rendered);
+        assert_eq!(missing_raw_entries(&markdown, &stored), vec![1]);
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path;
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::Mutex;
+
+use crate::config::FsyncPolicy;
+
+const ANCHOR: &str = r#"<a id="msg-"#;
+const SEPARATOR: &str = "\n---";
+
+/// Markdown archive: rendered entries are appended to a single file.
+///
+/// With [`FsyncPolicy::Always`] every entry is first written to a journal next to the file, so an append
+/// interrupted by a crash is completed when the file is opened again.
+#[derive(Debug)]
+pub struct MarkdownSink {
+    path: path::PathBuf,
+    fsync: FsyncPolicy,
+    /// `None` if opened read-only
+    file: Mutex<Option<File>>,
+    /// Ids of messages with an entry in the file, so a sync doesn't archive them again
+    archived: std::sync::Mutex<HashSet<i64>>,
+}
+
+impl MarkdownSink {
+    /// Opens the file for appending, creating it and its directory if needed.
+    /// Interrupted appends are recovered and a truncated trailing entry is cut off.
+    pub fn open<P: Into<path::PathBuf>>(path: P, fsync: FsyncPolicy) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(path.parent().context("data dir")?).context("create data dir")?;
+        recover_journal(&path).context("recover journal")?;
+        repair_tail(&path).context("repair data file")?;
+        let archived = match fs::read_to_string(&path) {
+            Ok(content) => archived_ids(&content),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err).context("read data file"),
+        };
+        Ok(Self {
+            file: Mutex::new(Some(open_append(&path)?)),
+            path,
+            fsync,
+            archived: std::sync::Mutex::new(archived),
+        })
+    }
+
+    /// Reads which messages are archived without creating or repairing anything, e.g. for a dry run.
+    /// Appends and rewrites fail.
+    pub fn open_read_only<P: Into<path::PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let archived = match fs::read_to_string(&path) {
+            Ok(content) => archived_ids(&content),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err).context("read data file"),
+        };
+        Ok(Self {
+            file: Mutex::new(None),
+            path,
+            fsync: FsyncPolicy::Never,
+            archived: std::sync::Mutex::new(archived),
+        })
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// Directory of the archive, downloaded attachments are stored there.
+    pub fn data_dir(&self) -> Result<&path::Path> {
+        self.path.parent().context("data dir")
+    }
+
+    /// Whether the file has an entry for the message.
+    pub fn contains(&self, message_id: i64) -> bool {
+        self.archived.lock().expect("archived lock poisoned").contains(&message_id)
+    }
+
+    pub async fn append(&self, message_id: i64, entry: &str) -> Result<()> {
+        let mut file = self.file.lock().await;
+        let file = file.as_mut().context("data file is opened read-only")?;
+        match self.fsync {
+            FsyncPolicy::Never => file.write_all(entry.as_bytes()).context("write to file")?,
+            FsyncPolicy::Always => {
+                let journal = journal_path(&self.path);
+                let len = file.metadata().context("data file metadata")?.len();
+                write_journal(&journal, len, entry)?;
+                file.write_all(entry.as_bytes()).context("write to file")?;
+                file.sync_data().context("sync data file")?;
+                fs::remove_file(&journal).context("remove journal")?;
+            }
+        }
+        self.archived.lock().expect("archived lock poisoned").insert(message_id);
+        Ok(())
+    }
+
+    /// Flushes appended entries to disk.
+    pub async fn sync(&self) -> Result<()> {
+        let mut file = self.file.lock().await;
+        let Some(file) = file.as_mut() else {
+            return Ok(());
+        };
+        file.flush().context("flush data file")?;
+        file.sync_all().context("sync data file")
+    }
+
+    /// Replaces an archived entry with the result of `f`, removing it if `f` returns `None`.
+    /// Returns `false` if there is no entry for the message.
+    pub async fn rewrite_entry<F>(&self, message_id: i64, f: F) -> Result<bool>
+    where
+        F: FnOnce(&str) -> Option<String>,
+    {
+        // hold the lock so nothing is appended while the file is rewritten
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            bail!("data file is opened read-only");
+        }
+        let content = fs::read_to_string(&self.path).context("read data file")?;
+        let (start, end) = match find_entry(&content, message_id) {
+            None => return Ok(false),
+            Some(range) => range,
+        };
+        let mut new_content = String::with_capacity(content.len());
+        new_content.push_str(&content[..start]);
+        let removed = match f(&content[start..end]) {
+            Some(entry) => {
+                new_content.push_str(&entry);
+                false
+            }
+            None => true,
+        };
+        new_content.push_str(&content[end..]);
+
+        // the file is replaced as a whole, so a crash leaves either the old or the new version
+        let tmp_path = suffixed(&self.path, ".tmp");
+        let mut tmp = File::create(&tmp_path).context("create temporary data file")?;
+        tmp.write_all(new_content.as_bytes()).context("write temporary data file")?;
+        tmp.sync_all().context("sync temporary data file")?;
+        fs::rename(&tmp_path, &self.path).context("replace data file")?;
+        // the append handle still points to the replaced file
+        *file = Some(open_append(&self.path)?);
+        if removed {
+            self.archived.lock().expect("archived lock poisoned").remove(&message_id);
+        }
+        Ok(true)
+    }
+}
+
+fn open_append(path: &path::Path) -> Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .context("open data file")
+}
+
+fn suffixed(path: &path::Path, suffix: &str) -> path::PathBuf {
+    let mut path = OsString::from(path.as_os_str());
+    path.push(suffix);
+    path.into()
+}
+
+/// Journal location: next to the data file, e.g. `data/data.md` -> `data/data.md.journal`.
+fn journal_path(path: &path::Path) -> path::PathBuf {
+    suffixed(path, ".journal")
+}
+
+/// Journal holds a single entry: a `<data file length> <entry length>` header line followed by the entry.
+fn write_journal(journal: &path::Path, data_len: u64, entry: &str) -> Result<()> {
+    let mut file = File::create(journal).context("create journal")?;
+    file.write_all(format!("{} {}\n", data_len, entry.len()).as_bytes())
+        .context("write journal")?;
+    file.write_all(entry.as_bytes()).context("write journal")?;
+    file.sync_all().context("sync journal")
+}
+
+fn parse_journal(content: &[u8]) -> Option<(u64, &[u8])> {
+    let header_end = content.iter().position(|b| *b == b'\n')?;
+    let header = std::str::from_utf8(&content[..header_end]).ok()?;
+    let (data_len, entry_len) = header.split_once(' ')?;
+    let entry = &content[header_end + 1..];
+    if entry.len() != entry_len.parse::<usize>().ok()? {
+        return None;
+    }
+    Some((data_len.parse().ok()?, entry))
+}
+
+/// Completes an append interrupted by a crash: the data file is truncated to its length before the append
+/// and the journaled entry is written again. An incomplete journal means the data file wasn't touched yet.
+fn recover_journal(path: &path::Path) -> Result<()> {
+    let journal = journal_path(path);
+    if !journal.exists() {
+        return Ok(());
+    }
+    let content = fs::read(&journal).context("read journal")?;
+    match parse_journal(&content) {
+        None => log::warn!("discarding incomplete journal {}", journal.display()),
+        Some((data_len, entry)) => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(path)
+                .context("open data file")?;
+            let len = file.metadata().context("data file metadata")?.len();
+            if len < data_len {
+                log::error!(
+                    "{} is shorter than before the interrupted write, discarding journal",
+                    path.display()
+                );
+            } else {
+                log::warn!("completing interrupted write to {}", path.display());
+                file.set_len(data_len).context("truncate data file")?;
+                let mut file = open_append(path)?;
+                file.write_all(entry).context("write to file")?;
+                file.sync_all().context("sync data file")?;
+            }
+        }
+    }
+    fs::remove_file(&journal).context("remove journal")
+}
+
+/// Cuts off an entry truncated by a crash, the cut part is kept in a `.partial` file next to the data file.
+fn repair_tail(path: &path::Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let content = fs::read(path).context("read data file")?;
+    let Some(start) = truncated_entry_start(&content) else {
+        return Ok(());
+    };
+    let tail = &content[start..];
+    log::warn!(
+        "{} ends with a truncated entry, moving it to .partial",
+        path.display()
+    );
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(suffixed(path, ".partial"))
+        .context("open partial file")?
+        .write_all(tail)
+        .context("write partial file")?;
+    let file = OpenOptions::new().write(true).open(path).context("open data file")?;
+    file.set_len(start as u64).context("truncate data file")?;
+    file.sync_all().context("sync data file")
+}
+
+/// Start of a truncated last entry. Entries start with the anchor at the beginning of a line and end with
+/// the separator, whatever their texts contain; anything else is not an entry and is left as it is.
+fn truncated_entry_start(content: &[u8]) -> Option<usize> {
+    let last_anchor = content
+        .windows(ANCHOR.len())
+        .rposition(|w| w == ANCHOR.as_bytes())
+        .filter(|&i| i == 0 || content[i - 1] == b'\n');
+    if let Some(start) = last_anchor {
+        let end = content.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(start, |i| i + 1);
+        if !content[start..end.max(start)].ends_with(SEPARATOR.as_bytes()) {
+            return Some(start);
+        }
+    }
+    // cut off inside the anchor of the next entry
+    let last_line = content.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    let tail = &content[last_line..];
+    (!tail.is_empty() && ANCHOR.as_bytes().starts_with(tail)).then_some(last_line)
+}
+
+/// Ids of messages whose anchors are in the file.
+pub fn archived_ids(content: &str) -> HashSet<i64> {
+    content
+        .match_indices(ANCHOR)
+        .filter_map(|(i, _)| {
+            let rest = &content[i + ANCHOR.len()..];
+            let (id, _) = rest.split_once('"')?;
+            id.parse().ok()
+        })
+        .collect()
+}
+
+/// Returns byte range of an archived entry: from its anchor up to the next entry's anchor, so separators
+/// in message texts, e.g. horizontal rules, don't cut it short.
+pub fn find_entry(content: &str, message_id: i64) -> Option<(usize, usize)> {
+    // the closing quote keeps `msg-1` from matching `msg-12`
+    let start = content.find(&format!(r#"{}{}""#, ANCHOR, message_id))?;
+    let end = content[start + ANCHOR.len()..]
+        .find(&format!("\n{}", ANCHOR))
+        .map(|i| start + ANCHOR.len() + i + 1)
+        .unwrap_or(content.len());
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{journal_path, MarkdownSink};
+    use crate::config::FsyncPolicy;
+
+    const FIRST: &str = "<a id=\"msg-1\" data-type=\"text\"></a>\n\n**Date:** 2023-11-14 22:13:20\n\nfirst\n\n---\n\n";
+    const SECOND: &str = "<a id=\"msg-2\" data-type=\"text\"></a>\n\n**Date:** 2023-11-14 22:13:20\n\nsecond\n\n---\n\n";
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("teleforward-sink-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("data.md")
+    }
+
+    #[test]
+    fn test_truncated_entry_is_cut_off() {
+        let path = test_path("tail");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format!("{}{}", FIRST, &SECOND[..40])).unwrap();
+
+        MarkdownSink::open(&path, FsyncPolicy::Always).expect("cannot open sink");
+        assert_eq!(fs::read_to_string(&path).unwrap(), FIRST);
+        assert_eq!(fs::read_to_string(path.with_extension("md.partial")).unwrap(), &SECOND[..40]);
+    }
+
+    #[test]
+    fn test_interrupted_append_is_completed() {
+        let path = test_path("journal");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // crashed after the journal was written and part of the entry was appended
+        fs::write(&path, format!("{}{}", FIRST, &SECOND[..10])).unwrap();
+        fs::write(journal_path(&path), format!("{} {}\n{}", FIRST.len(), SECOND.len(), SECOND)).unwrap();
+
+        MarkdownSink::open(&path, FsyncPolicy::Always).expect("cannot open sink");
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}{}", FIRST, SECOND));
+        assert!(!journal_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_open_read_only() {
+        let path = test_path("read-only");
+        let sink = MarkdownSink::open_read_only(&path).expect("cannot open sink");
+        assert!(sink.append(1, FIRST).await.is_err());
+        sink.sync().await.expect("cannot sync");
+        assert!(!path.parent().unwrap().exists());
+
+        // a truncated tail is left as is
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format!("{}{}", FIRST, &SECOND[..40])).unwrap();
+        let sink = MarkdownSink::open_read_only(&path).expect("cannot open sink");
+        assert!(sink.contains(1));
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}{}", FIRST, &SECOND[..40]));
+    }
+
+    #[tokio::test]
+    async fn test_entry_with_horizontal_rule() {
+        let ruled = FIRST.replace("first", "before\n\n---\n\nafter");
+        let path = test_path("rule");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // truncated after the rule in the text
+        let truncated = &ruled[..ruled.find("after").unwrap() + 2];
+        fs::write(&path, format!("{}{}", SECOND, truncated)).unwrap();
+
+        let sink = MarkdownSink::open(&path, FsyncPolicy::Never).expect("cannot open sink");
+        assert_eq!(fs::read_to_string(&path).unwrap(), SECOND);
+        sink.append(1, &ruled).await.expect("cannot append");
+        assert!(sink.rewrite_entry(1, |entry| Some(entry.replace("after", "changed"))).await.expect("cannot rewrite"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}{}", SECOND, ruled.replace("after", "changed")));
+        assert!(sink.rewrite_entry(2, |_| None).await.expect("cannot rewrite"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), ruled.replace("after", "changed"));
+    }
+
+    #[tokio::test]
+    async fn test_archived_entries() {
+        let path = test_path("archived");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, FIRST).unwrap();
+
+        let sink = MarkdownSink::open(&path, FsyncPolicy::Never).expect("cannot open sink");
+        assert!(sink.contains(1));
+        assert!(!sink.contains(2));
+        sink.append(2, SECOND).await.expect("cannot append");
+        assert!(sink.contains(2));
+        assert!(sink.rewrite_entry(1, |_| None).await.expect("cannot rewrite"));
+        assert!(!sink.contains(1));
+    }
+}
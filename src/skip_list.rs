@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+
+/// Messages excluded with `/skip`, one id per line in a file next to the output, so they stay excluded
+/// after a restart and in `teleforward sync`.
+#[derive(Debug)]
+pub struct SkipList {
+    path: path::PathBuf,
+    ids: Mutex<HashSet<i64>>,
+}
+
+impl SkipList {
+    pub fn open<P: Into<path::PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let ids = match fs::read_to_string(&path) {
+            Ok(content) => content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| match line.trim().parse() {
+                    Ok(id) => Some(id),
+                    Err(err) => {
+                        log::warn!("skip invalid line {:?} of {}: {}", line, path.display(), err);
+                        None
+                    }
+                })
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err).context(format!("read {}", path.display())),
+        };
+        Ok(Self {
+            path,
+            ids: Mutex::new(ids),
+        })
+    }
+
+    /// Default location: next to the data file, e.g. `data/data.md` -> `data/data.skipped`.
+    pub fn default_path(file_path: &str) -> path::PathBuf {
+        path::Path::new(file_path).with_extension("skipped")
+    }
+
+    pub fn contains(&self, message_id: i64) -> bool {
+        self.ids.lock().expect("skip list lock poisoned").contains(&message_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.lock().expect("skip list lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Excludes the message and saves it to the file.
+    pub fn insert(&self, message_id: i64) -> Result<()> {
+        let mut ids = self.ids.lock().expect("skip list lock poisoned");
+        if ids.contains(&message_id) {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context("create data dir")?;
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .context("open skip list")?;
+        file.write_all(format!("{}\n", message_id).as_bytes())
+            .context("write skip list")?;
+        file.sync_data().context("sync skip list")?;
+        ids.insert(message_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::SkipList;
+
+    #[test]
+    fn test_skip_list_is_persisted() {
+        let dir = std::env::temp_dir().join(format!("teleforward-skip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("data.skipped");
+
+        let skip_list = SkipList::open(&path).expect("cannot open skip list");
+        assert!(skip_list.is_empty());
+        skip_list.insert(5).expect("cannot insert");
+        skip_list.insert(5).expect("cannot insert");
+        skip_list.insert(7).expect("cannot insert");
+
+        let reopened = SkipList::open(&path).expect("cannot open skip list");
+        assert!(reopened.contains(5) && reopened.contains(7));
+        assert_eq!(reopened.len(), 2);
+    }
+}
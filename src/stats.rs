@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::index::{SearchIndex, DATE_FORMAT};
+use crate::logging::mask_phone;
+
+const TOP_ORIGINS: i64 = 10;
+const HISTOGRAM_WIDTH: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountStats {
+    pub account: String,
+    pub sources: Vec<SourceStats>,
+    pub top_origins: Vec<OriginStats>,
+    /// Messages per day of all sources, keyed by `YYYY-MM-DD`
+    pub per_day: BTreeMap<String, u64>,
+    pub last_archived: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SourceStats {
+    pub source: String,
+    pub messages: u64,
+    pub by_type: BTreeMap<String, u64>,
+    pub attachments: u64,
+    pub attachments_size: u64,
+    /// Messages per day, keyed by `YYYY-MM-DD`
+    pub per_day: BTreeMap<String, u64>,
+    pub last_archived: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OriginStats {
+    pub source: String,
+    pub origin: String,
+    pub messages: u64,
+}
+
+pub fn collect(index: &SearchIndex, account: &str) -> Result<AccountStats> {
+    let conn = index.connection();
+
+    let mut sources: BTreeMap<String, SourceStats> = BTreeMap::new();
+    let mut stmt = conn
+        .prepare(
+            r#"
+SELECT COALESCE(source, '-'), content_type, COUNT(*), SUM(attachments), SUM(attachments_size), MAX(date)
+FROM entries
+WHERE account = ?1
+GROUP BY 1, 2
+"#,
+        )
+        .context("prepare sources query")?;
+    let mut rows = stmt.query(params![account]).context("query sources")?;
+    while let Some(row) = rows.next().context("read sources")? {
+        let source: String = row.get(0)?;
+        let content_type: String = row.get(1)?;
+        let messages: i64 = row.get(2)?;
+        let attachments: i64 = row.get(3)?;
+        let attachments_size: i64 = row.get(4)?;
+        let last_date = format_timestamp(row.get(5)?);
+
+        let stats = sources.entry(source.clone()).or_insert_with(|| SourceStats {
+            source,
+            ..Default::default()
+        });
+        stats.messages += messages as u64;
+        stats.by_type.insert(content_type, messages as u64);
+        stats.attachments += attachments as u64;
+        stats.attachments_size += attachments_size as u64;
+        // dates are formatted as `YYYY-MM-DD HH:MM:SS`, so they can be compared as strings
+        if stats.last_archived < last_date {
+            stats.last_archived = last_date;
+        }
+    }
+    drop(rows);
+
+    let mut stmt = conn
+        .prepare(
+            r#"
+SELECT COALESCE(source, '-'), origin, COUNT(*)
+FROM entries
+WHERE account = ?1 AND origin IS NOT NULL
+GROUP BY 1, 2
+ORDER BY 3 DESC
+LIMIT ?2
+"#,
+        )
+        .context("prepare origins query")?;
+    let top_origins = stmt
+        .query_map(params![account, TOP_ORIGINS], |row| {
+            Ok(OriginStats {
+                source: row.get(0)?,
+                origin: row.get(1)?,
+                messages: row.get::<_, i64>(2)? as u64,
+            })
+        })
+        .context("query origins")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read origins")?;
+
+    let mut stmt = conn
+        .prepare(
+            r#"
+SELECT COALESCE(source, '-'), date(date, 'unixepoch'), COUNT(*)
+FROM entries
+WHERE account = ?1
+GROUP BY 1, 2
+"#,
+        )
+        .context("prepare histogram query")?;
+    let mut per_day: BTreeMap<String, u64> = BTreeMap::new();
+    let mut rows = stmt.query(params![account]).context("query histogram")?;
+    while let Some(row) = rows.next().context("read histogram")? {
+        let source: String = row.get(0)?;
+        let day: String = row.get(1)?;
+        let messages = row.get::<_, i64>(2)? as u64;
+        *per_day.entry(day.clone()).or_default() += messages;
+        if let Some(stats) = sources.get_mut(&source) {
+            stats.per_day.insert(day, messages);
+        }
+    }
+    drop(rows);
+
+    let last_archived = sources.values().filter_map(|s| s.last_archived.clone()).max();
+    Ok(AccountStats {
+        account: account.to_string(),
+        sources: sources.into_values().collect(),
+        top_origins,
+        per_day,
+        last_archived,
+    })
+}
+
+/// Collects statistics of the configured accounts, or only of the one with `phone`, from their indexes and prints them.
+pub fn print_accounts(config: &Config, phone: Option<&str>, format: OutputFormat) -> Result<()> {
+    let mut all_stats = Vec::new();
+    for account in config.accounts.iter() {
+        if phone.is_some_and(|phone| phone != account.phone) {
+            continue;
+        }
+        let index =
+            SearchIndex::open_read_only(account.index_path()).context(format!("open index of {}", mask_phone(&account.phone)))?;
+        all_stats.push(collect(&index, &account.phone).context(format!("collect stats of {}", mask_phone(&account.phone)))?);
+    }
+    match format {
+        OutputFormat::Table => all_stats.iter().for_each(print_table),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&all_stats).context("serialize stats")?),
+    }
+    Ok(())
+}
+
+fn format_timestamp(timestamp: Option<i64>) -> Option<String> {
+    timestamp
+        .and_then(|t| NaiveDateTime::from_timestamp_opt(t, 0))
+        .map(|d| d.format(DATE_FORMAT).to_string())
+}
+
+pub fn print_table(stats: &AccountStats) {
+    println!("Account: {}", stats.account);
+    println!(
+        "Last archived: {}",
+        stats.last_archived.as_deref().unwrap_or("-")
+    );
+    println!();
+    println!(
+        "{:<32} {:>8} {:>12} {:>14}  {:<19}  types",
+        "source", "messages", "attachments", "bytes", "last archived"
+    );
+    for source in stats.sources.iter() {
+        let types = source
+            .by_type
+            .iter()
+            .map(|(t, c)| format!("{}={}", t, c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!(
+            "{:<32} {:>8} {:>12} {:>14}  {:<19}  {}",
+            source.source,
+            source.messages,
+            source.attachments,
+            source.attachments_size,
+            source.last_archived.as_deref().unwrap_or("-"),
+            types
+        );
+    }
+
+    if !stats.top_origins.is_empty() {
+        println!();
+        println!("{:<32} {:<12} {:>8}", "forwarded from", "origin", "messages");
+        for origin in stats.top_origins.iter() {
+            println!(
+                "{:<32} {:<12} {:>8}",
+                origin.source, origin.origin, origin.messages
+            );
+        }
+    }
+
+    if !stats.per_day.is_empty() {
+        println!();
+        println!("all sources");
+        print_histogram(&stats.per_day);
+        for source in stats.sources.iter() {
+            println!();
+            println!("{}", source.source);
+            print_histogram(&source.per_day);
+        }
+    }
+    println!();
+}
+
+fn print_histogram(per_day: &BTreeMap<String, u64>) {
+    let Some(max) = per_day.values().max() else {
+        return;
+    };
+    for (day, count) in per_day.iter() {
+        let bar = (count * HISTOGRAM_WIDTH).div_ceil(*max);
+        println!("{} {:>6} {}", day, count, "#".repeat(bar as usize));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::collect;
+    use crate::index::{IndexEntry, SearchIndex};
+
+    fn entry(account: &str, message_id: i64, day: u32, source: Option<&str>, content_type: &str) -> IndexEntry {
+        IndexEntry {
+            account: account.to_string(),
+            message_id,
+            date: NaiveDate::from_ymd_opt(2023, 11, day).unwrap().and_hms_opt(12, 0, 0).unwrap(),
+            source: source.map(|s| s.to_string()),
+            content_type: content_type.to_string(),
+            origin: source.map(|_| "channel".to_string()),
+            attachments: u64::from(content_type == "photo"),
+            attachments_size: if content_type == "photo" { 1000 } else { 0 },
+            text: format!("message {}", message_id),
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        let path = std::env::temp_dir().join(format!("teleforward-stats-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let index = SearchIndex::open(&path).expect("cannot open index");
+        let entries = [
+            entry("+1", 1, 1, Some("@rustnews"), "text"),
+            entry("+1", 2, 1, Some("@rustnews"), "photo"),
+            entry("+1", 3, 2, Some("@rustnews"), "photo"),
+            entry("+1", 4, 2, None, "text"),
+            // other accounts are not counted
+            entry("+2", 5, 2, Some("@rustnews"), "text"),
+        ];
+        for e in entries.iter() {
+            index.add(e).expect("cannot add");
+        }
+
+        let stats = collect(&index, "+1").expect("cannot collect stats");
+        assert_eq!(stats.last_archived.as_deref(), Some("2023-11-02 12:00:00"));
+        assert_eq!(stats.per_day.get("2023-11-01"), Some(&2));
+        assert_eq!(stats.per_day.get("2023-11-02"), Some(&2));
+
+        assert_eq!(stats.sources.len(), 2);
+        let own = &stats.sources[0];
+        assert_eq!((own.source.as_str(), own.messages), ("-", 1));
+        let rustnews = &stats.sources[1];
+        assert_eq!(rustnews.source, "@rustnews");
+        assert_eq!(rustnews.messages, 3);
+        assert_eq!(rustnews.by_type.get("photo"), Some(&2));
+        assert_eq!((rustnews.attachments, rustnews.attachments_size), (2, 2000));
+        assert_eq!(rustnews.per_day.get("2023-11-01"), Some(&2));
+        assert_eq!(rustnews.per_day.get("2023-11-02"), Some(&1));
+
+        assert_eq!(stats.top_origins.len(), 1);
+        assert_eq!(stats.top_origins[0].messages, 3);
+    }
+}
@@ -0,0 +1,196 @@
+use std::fmt::Debug;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rust_tdlib::client::tdlib_client::TdJson;
+use rust_tdlib::client::Client;
+use rust_tdlib::types::{
+    Chat, Close, CreatePrivateChat, DownloadFile, FormattedText, GetChat, GetChatHistory, GetMe,
+    GetMessage, GetMessageLink, GetSupergroup, GetUser, InputMessageContent, InputMessageReplyTo,
+    InputMessageReplyToMessage, InputMessageText, Message, SendMessage, Supergroup, User,
+};
+
+pub mod cache;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fake;
+pub mod metered;
+pub mod rate_limit;
+pub mod record;
+
+/// Telegram calls used by teleforward.
+/// Implemented by [`TdlibApi`] for real accounts and by the in-memory `fake::FakeTelegram` for tests.
+#[async_trait]
+pub trait TelegramApi: Debug + Send + Sync {
+    async fn get_me(&self) -> Result<User>;
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat>;
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup>;
+
+    async fn get_user(&self, user_id: i64) -> Result<User>;
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message>;
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String>;
+
+    /// Returns up to `limit` messages older than `from_message_id`, newest first.
+    /// `from_message_id` equal to 0 means the last message of the chat.
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>>;
+
+    /// Downloads a file synchronously and returns its local path.
+    async fn download_file(&self, file_id: i32) -> Result<String>;
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat>;
+
+    async fn send_text(&self, chat_id: i64, reply_to_message_id: i64, text: String) -> Result<()>;
+
+    /// Closes the client, TDLib keeps its session in the database directory.
+    async fn close(&self) -> Result<()>;
+}
+
+/// [`TelegramApi`] backed by a TDLib client bound to a worker.
+#[derive(Debug)]
+pub struct TdlibApi {
+    client: Client<TdJson>,
+}
+
+impl TdlibApi {
+    pub fn new(client: Client<TdJson>) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl TelegramApi for TdlibApi {
+    async fn get_me(&self) -> Result<User> {
+        self.client
+            .get_me(GetMe::builder().build())
+            .await
+            .context("telegram:get_me")
+    }
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat> {
+        self.client
+            .get_chat(GetChat::builder().chat_id(chat_id).build())
+            .await
+            .context("telegram:get_chat")
+    }
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup> {
+        self.client
+            .get_supergroup(GetSupergroup::builder().supergroup_id(supergroup_id))
+            .await
+            .context("telegram:get_supergroup")
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<User> {
+        self.client
+            .get_user(GetUser::builder().user_id(user_id).build())
+            .await
+            .context("telegram:get_user")
+    }
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message> {
+        self.client
+            .get_message(
+                GetMessage::builder()
+                    .chat_id(chat_id)
+                    .message_id(message_id)
+                    .build(),
+            )
+            .await
+            .context("telegram:get_message")
+    }
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String> {
+        let link = self
+            .client
+            .get_message_link(
+                GetMessageLink::builder()
+                    .chat_id(chat_id)
+                    .message_id(message_id)
+                    .build(),
+            )
+            .await
+            .context("telegram:get_message_link")?;
+        Ok(link.link().clone())
+    }
+
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>> {
+        let messages = self
+            .client
+            .get_chat_history(
+                GetChatHistory::builder()
+                    .chat_id(chat_id)
+                    .offset(0)
+                    .from_message_id(from_message_id)
+                    .limit(limit),
+            )
+            .await
+            .context("telegram:get_chat_history")?;
+        Ok(messages.messages().iter().flatten().cloned().collect())
+    }
+
+    async fn download_file(&self, file_id: i32) -> Result<String> {
+        let file = self
+            .client
+            .download_file(
+                DownloadFile::builder()
+                    .file_id(file_id)
+                    .synchronous(true)
+                    .priority(1)
+                    .build(),
+            )
+            .await
+            .context("telegram:download_file")?;
+        Ok(file.local().path().clone())
+    }
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat> {
+        self.client
+            .create_private_chat(CreatePrivateChat::builder().user_id(user_id).build())
+            .await
+            .context("telegram:create_private_chat")
+    }
+
+    async fn send_text(&self, chat_id: i64, reply_to_message_id: i64, text: String) -> Result<()> {
+        self.client
+            .send_message(
+                SendMessage::builder()
+                    .chat_id(chat_id)
+                    .reply_to(InputMessageReplyTo::Message(
+                        InputMessageReplyToMessage::builder()
+                            .chat_id(chat_id)
+                            .message_id(reply_to_message_id)
+                            .build(),
+                    ))
+                    .input_message_content(InputMessageContent::InputMessageText(
+                        InputMessageText::builder()
+                            .text(FormattedText::builder().text(text).build())
+                            .build(),
+                    ))
+                    .build(),
+            )
+            .await
+            .context("telegram:send_message")?;
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.client
+            .close(Close::builder().build())
+            .await
+            .context("telegram:close")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rust_tdlib::types::{Chat, Message, Supergroup, User};
+use serde::{Deserialize, Serialize};
+
+use super::TelegramApi;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Cached<T> {
+    value: T,
+    /// Unix time the value was fetched or updated at
+    updated_at: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entries {
+    chats: HashMap<i64, Cached<Chat>>,
+    users: HashMap<i64, Cached<User>>,
+    supergroups: HashMap<i64, Cached<Supergroup>>,
+}
+
+/// Chats, users and supergroups of an account, so sources of messages are not requested for every message.
+/// Filled by lookups and by updates TDLib sends anyway; values older than `ttl` are requested again.
+#[derive(Debug)]
+pub struct MetadataCache {
+    ttl: Duration,
+    path: Option<PathBuf>,
+    entries: RwLock<Entries>,
+}
+
+impl MetadataCache {
+    /// Values are kept in memory only unless `path` is set, then they are loaded from it and saved on [`Self::save`].
+    /// A missing or unreadable file leaves the cache empty.
+    pub fn new(ttl: Duration, path: Option<PathBuf>) -> Self {
+        let entries = match &path {
+            Some(path) if path.exists() => match load(path) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    log::warn!("cannot load metadata cache, starting empty: {:#}", err);
+                    Entries::default()
+                }
+            },
+            _ => Entries::default(),
+        };
+        Self {
+            ttl,
+            path,
+            entries: RwLock::new(entries),
+        }
+    }
+
+    fn entries(&self) -> std::sync::RwLockReadGuard<'_, Entries> {
+        self.entries.read().expect("cache lock poisoned")
+    }
+
+    fn entries_mut(&self) -> std::sync::RwLockWriteGuard<'_, Entries> {
+        self.entries.write().expect("cache lock poisoned")
+    }
+
+    fn fresh<T: Clone>(&self, cached: Option<&Cached<T>>) -> Option<T> {
+        let cached = cached?;
+        let age = chrono::Utc::now().timestamp() - cached.updated_at;
+        (age >= 0 && (age as u64) < self.ttl.as_secs()).then(|| cached.value.clone())
+    }
+
+    pub fn chat(&self, chat_id: i64) -> Option<Chat> {
+        self.fresh(self.entries().chats.get(&chat_id))
+    }
+
+    pub fn user(&self, user_id: i64) -> Option<User> {
+        self.fresh(self.entries().users.get(&user_id))
+    }
+
+    pub fn supergroup(&self, supergroup_id: i64) -> Option<Supergroup> {
+        self.fresh(self.entries().supergroups.get(&supergroup_id))
+    }
+
+    pub fn insert_chat(&self, chat: Chat) {
+        self.entries_mut().chats.insert(chat.id(), cached(chat));
+    }
+
+    pub fn insert_user(&self, user: User) {
+        self.entries_mut().users.insert(user.id(), cached(user));
+    }
+
+    pub fn insert_supergroup(&self, supergroup: Supergroup) {
+        self.entries_mut().supergroups.insert(supergroup.id(), cached(supergroup));
+    }
+
+    /// Drops a chat whose title or other fields changed, it is requested again on the next lookup.
+    pub fn invalidate_chat(&self, chat_id: i64) {
+        self.entries_mut().chats.remove(&chat_id);
+    }
+
+    /// Writes the cache to its file, if it has one.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = serde_json::to_vec(&*self.entries()).context("serialize metadata cache")?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).context(format!("write {}", tmp.display()))?;
+        fs::rename(&tmp, path).context(format!("rename {} to {}", tmp.display(), path.display()))
+    }
+}
+
+fn cached<T>(value: T) -> Cached<T> {
+    Cached {
+        value,
+        updated_at: chrono::Utc::now().timestamp(),
+    }
+}
+
+fn load(path: &Path) -> Result<Entries> {
+    let content = fs::read(path).context(format!("read {}", path.display()))?;
+    serde_json::from_slice(&content).context(format!("parse {}", path.display()))
+}
+
+/// Wraps another [`TelegramApi`] and serves chats, users and supergroups from a [`MetadataCache`].
+#[derive(Debug)]
+pub struct CachedApi {
+    inner: Box<dyn TelegramApi>,
+    cache: Arc<MetadataCache>,
+}
+
+impl CachedApi {
+    pub fn new(inner: Box<dyn TelegramApi>, cache: Arc<MetadataCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+#[async_trait]
+impl TelegramApi for CachedApi {
+    async fn get_me(&self) -> Result<User> {
+        self.inner.get_me().await
+    }
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat> {
+        if let Some(chat) = self.cache.chat(chat_id) {
+            return Ok(chat);
+        }
+        let chat = self.inner.get_chat(chat_id).await?;
+        self.cache.insert_chat(chat.clone());
+        Ok(chat)
+    }
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup> {
+        if let Some(supergroup) = self.cache.supergroup(supergroup_id) {
+            return Ok(supergroup);
+        }
+        let supergroup = self.inner.get_supergroup(supergroup_id).await?;
+        self.cache.insert_supergroup(supergroup.clone());
+        Ok(supergroup)
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<User> {
+        if let Some(user) = self.cache.user(user_id) {
+            return Ok(user);
+        }
+        let user = self.inner.get_user(user_id).await?;
+        self.cache.insert_user(user.clone());
+        Ok(user)
+    }
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message> {
+        self.inner.get_message(chat_id, message_id).await
+    }
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String> {
+        self.inner.get_message_link(chat_id, message_id).await
+    }
+
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>> {
+        self.inner.get_chat_history(chat_id, from_message_id, limit).await
+    }
+
+    async fn download_file(&self, file_id: i32) -> Result<String> {
+        self.inner.download_file(file_id).await
+    }
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat> {
+        self.inner.create_private_chat(user_id).await
+    }
+
+    async fn send_text(&self, chat_id: i64, reply_to_message_id: i64, text: String) -> Result<()> {
+        self.inner.send_text(chat_id, reply_to_message_id, text).await
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rust_tdlib::types::User;
+
+    use super::{CachedApi, MetadataCache};
+    use crate::telegram::fake::FakeTelegram;
+    use crate::telegram::TelegramApi;
+
+    fn user(id: i64, first_name: &str) -> User {
+        User::from_json(&format!(r#"{{"@type":"user","id":{},"first_name":"{}"}}"#, id, first_name))
+            .expect("cannot parse json")
+    }
+
+    #[tokio::test]
+    async fn test_cached_api() {
+        let path = std::env::temp_dir().join(format!("teleforward-cache-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let cache = Arc::new(MetadataCache::new(Duration::from_secs(60), Some(path.clone())));
+        // the fake only knows the authorized user, others come from updates
+        cache.insert_user(user(2, "Cached"));
+        let api = CachedApi::new(Box::new(FakeTelegram::new(user(1, "Me"))), cache.clone());
+        assert_eq!(api.get_user(2).await.expect("cached user").first_name(), "Cached");
+        api.get_user(1).await.expect("fetched user");
+        assert!(cache.user(1).is_some());
+        assert!(api.get_user(3).await.is_err());
+
+        cache.save().expect("cannot save cache");
+        let loaded = MetadataCache::new(Duration::from_secs(60), Some(path.clone()));
+        assert_eq!(loaded.user(2).expect("loaded user").first_name(), "Cached");
+        let expired = MetadataCache::new(Duration::ZERO, Some(path));
+        assert!(expired.user(2).is_none());
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rust_tdlib::types::{Chat, Message, Supergroup, User};
+
+use super::TelegramApi;
+
+#[derive(Debug, Default)]
+struct State {
+    me: Option<User>,
+    chats: HashMap<i64, Chat>,
+    supergroups: HashMap<i64, Supergroup>,
+    users: HashMap<i64, User>,
+    messages: BTreeMap<(i64, i64), Message>,
+    links: HashMap<(i64, i64), String>,
+    files: HashMap<i32, String>,
+    sent: Vec<(i64, String)>,
+    closed: bool,
+}
+
+/// Scriptable in-memory [`TelegramApi`]: tests put objects in and the fake serves them
+/// the way TDLib would, returning an error for anything unknown.
+#[derive(Debug, Default)]
+pub struct FakeTelegram {
+    state: Mutex<State>,
+}
+
+impl FakeTelegram {
+    pub fn new(me: User) -> Self {
+        let fake = Self::default();
+        fake.add_user(me.clone());
+        fake.state().me = Some(me);
+        fake
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("fake state lock poisoned")
+    }
+
+    pub fn add_chat(&self, chat: Chat) {
+        self.state().chats.insert(chat.id(), chat);
+    }
+
+    pub fn add_supergroup(&self, supergroup: Supergroup) {
+        self.state().supergroups.insert(supergroup.id(), supergroup);
+    }
+
+    pub fn add_user(&self, user: User) {
+        self.state().users.insert(user.id(), user);
+    }
+
+    pub fn add_message(&self, message: Message) {
+        self.state()
+            .messages
+            .insert((message.chat_id(), message.id()), message);
+    }
+
+    pub fn add_message_link(&self, chat_id: i64, message_id: i64, link: &str) {
+        self.state()
+            .links
+            .insert((chat_id, message_id), link.to_string());
+    }
+
+    /// Makes `download_file` return `local_path` for the file.
+    pub fn add_file(&self, file_id: i32, local_path: &str) {
+        self.state().files.insert(file_id, local_path.to_string());
+    }
+
+    /// Texts sent with `send_text`, in order.
+    pub fn sent(&self) -> Vec<(i64, String)> {
+        self.state().sent.clone()
+    }
+
+    /// Whether `close` was called.
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+}
+
+#[async_trait]
+impl TelegramApi for FakeTelegram {
+    async fn get_me(&self) -> Result<User> {
+        self.state().me.clone().ok_or_else(|| anyhow!("not authorized"))
+    }
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat> {
+        self.state()
+            .chats
+            .get(&chat_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("chat {} not found", chat_id))
+    }
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup> {
+        self.state()
+            .supergroups
+            .get(&supergroup_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("supergroup {} not found", supergroup_id))
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<User> {
+        self.state()
+            .users
+            .get(&user_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("user {} not found", user_id))
+    }
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message> {
+        self.state()
+            .messages
+            .get(&(chat_id, message_id))
+            .cloned()
+            .ok_or_else(|| anyhow!("message {} not found in chat {}", message_id, chat_id))
+    }
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String> {
+        self.state()
+            .links
+            .get(&(chat_id, message_id))
+            .cloned()
+            .ok_or_else(|| anyhow!("message link is unavailable"))
+    }
+
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>> {
+        let upper = if from_message_id == 0 {
+            i64::MAX
+        } else {
+            from_message_id
+        };
+        Ok(self
+            .state()
+            .messages
+            .range((chat_id, i64::MIN)..(chat_id, upper))
+            .rev()
+            .take(limit as usize)
+            .map(|(_, m)| m.clone())
+            .collect())
+    }
+
+    async fn download_file(&self, file_id: i32) -> Result<String> {
+        self.state()
+            .files
+            .get(&file_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("file {} not found", file_id))
+    }
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat> {
+        self.get_chat(user_id).await
+    }
+
+    async fn send_text(&self, chat_id: i64, _reply_to_message_id: i64, text: String) -> Result<()> {
+        self.state().sent.push((chat_id, text));
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.state().closed = true;
+        Ok(())
+    }
+}
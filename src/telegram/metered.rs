@@ -0,0 +1,100 @@
+use std::future::Future;
+use std::time::Instant;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rust_tdlib::types::{Chat, Message, Supergroup, User};
+
+use super::TelegramApi;
+use crate::metrics::metrics;
+
+/// Wraps another [`TelegramApi`] and measures duration of every request.
+#[derive(Debug)]
+pub struct MeteredApi {
+    inner: Box<dyn TelegramApi>,
+    account: String,
+}
+
+impl MeteredApi {
+    pub fn new(inner: Box<dyn TelegramApi>, account: &str) -> Self {
+        Self {
+            inner,
+            account: account.to_string(),
+        }
+    }
+
+    async fn measure<T, F>(&self, method: &str, request: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let started = Instant::now();
+        let result = request.await;
+        metrics()
+            .request_duration
+            .with_label_values(&[&self.account, method])
+            .observe(started.elapsed().as_secs_f64());
+        result
+    }
+}
+
+#[async_trait]
+impl TelegramApi for MeteredApi {
+    async fn get_me(&self) -> Result<User> {
+        self.measure("get_me", self.inner.get_me()).await
+    }
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat> {
+        self.measure("get_chat", self.inner.get_chat(chat_id)).await
+    }
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup> {
+        self.measure("get_supergroup", self.inner.get_supergroup(supergroup_id)).await
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<User> {
+        self.measure("get_user", self.inner.get_user(user_id)).await
+    }
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message> {
+        self.measure("get_message", self.inner.get_message(chat_id, message_id)).await
+    }
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String> {
+        self.measure("get_message_link", self.inner.get_message_link(chat_id, message_id))
+            .await
+    }
+
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>> {
+        self.measure(
+            "get_chat_history",
+            self.inner.get_chat_history(chat_id, from_message_id, limit),
+        )
+        .await
+    }
+
+    async fn download_file(&self, file_id: i32) -> Result<String> {
+        self.measure("download_file", self.inner.download_file(file_id)).await
+    }
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat> {
+        self.measure("create_private_chat", self.inner.create_private_chat(user_id))
+            .await
+    }
+
+    async fn send_text(&self, chat_id: i64, reply_to_message_id: i64, text: String) -> Result<()> {
+        self.measure(
+            "send_text",
+            self.inner.send_text(chat_id, reply_to_message_id, text),
+        )
+        .await
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.measure("close", self.inner.close()).await
+    }
+}
@@ -0,0 +1,260 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rust_tdlib::types::{Chat, Message, Supergroup, User};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use super::TelegramApi;
+use crate::metrics::metrics;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Longest a request waits for flood waits in total, longer ones fail the request
+const MAX_FLOOD_WAIT: Duration = Duration::from_secs(600);
+/// TDLib error codes of failures worth retrying: too many requests and internal errors, e.g. `Request aborted`
+const TRANSIENT_CODES: [u64; 2] = [429, 500];
+
+/// What to do with a failed request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Retry {
+    /// Telegram asked to wait, e.g. `FLOOD_WAIT_30` or `Too Many Requests: retry after 30`
+    After(Duration),
+    /// Transient failure, retried with exponential backoff
+    Backoff,
+    Fail,
+}
+
+/// Wraps another [`TelegramApi`] and keeps the account within telegram's limits: requests are spaced evenly,
+/// flood waits are honored by pausing all requests of the account, and transient errors are retried with
+/// exponential backoff. Requests which are not idempotent, like `send_text`, are only retried after a flood wait,
+/// since telegram rejected them. A request is attempted at most `MAX_ATTEMPTS` times and waits for flood waits
+/// up to `MAX_FLOOD_WAIT` in total, then the error is returned.
+#[derive(Debug)]
+pub struct RateLimitedApi {
+    inner: Box<dyn TelegramApi>,
+    account: String,
+    interval: Duration,
+    next_request: Mutex<Instant>,
+}
+
+impl RateLimitedApi {
+    /// `requests_per_second` of 0 disables spacing, flood waits and transient errors are still handled.
+    pub fn new(inner: Box<dyn TelegramApi>, account: &str, requests_per_second: f64) -> Self {
+        let interval = if requests_per_second > 0.0 {
+            Duration::from_secs_f64(1.0 / requests_per_second)
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            inner,
+            account: account.to_string(),
+            interval,
+            next_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the account's next request slot.
+    async fn throttle(&self) {
+        let mut next_request = self.next_request.lock().await;
+        tokio::time::sleep_until(*next_request).await;
+        *next_request = Instant::now() + self.interval;
+    }
+
+    /// Pauses all requests of the account.
+    async fn pause(&self, wait: Duration) {
+        let mut next_request = self.next_request.lock().await;
+        *next_request = (*next_request).max(Instant::now() + wait);
+    }
+
+    async fn call<T, F, Fut>(&self, method: &str, idempotent: bool, request: F) -> Result<T>
+    where
+        F: Fn() -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T>> + Send,
+        T: Send,
+    {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut flood_wait = Duration::ZERO;
+        let mut attempt = 1;
+        loop {
+            self.throttle().await;
+            let err = match request().await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            let wait = match classify(&error_text(&err)) {
+                Retry::After(wait) => {
+                    log::warn!("{}: flood wait, pausing requests for {:?}", method, wait);
+                    // other requests of the account wait even if this one gives up
+                    self.pause(wait).await;
+                    flood_wait += wait;
+                    if attempt >= MAX_ATTEMPTS || flood_wait > MAX_FLOOD_WAIT {
+                        return Err(err);
+                    }
+                    wait
+                }
+                Retry::Backoff if idempotent && attempt < MAX_ATTEMPTS => {
+                    log::warn!("{} failed, attempt {}, retry in {:?}: {:#}", method, attempt, backoff, err);
+                    let wait = backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    wait
+                }
+                Retry::Backoff | Retry::Fail => return Err(err),
+            };
+            metrics()
+                .request_retries
+                .with_label_values(&[&self.account, method])
+                .inc();
+            attempt += 1;
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Error with all its causes, TDLib errors are only recognizable by their code and message.
+fn error_text(err: &anyhow::Error) -> String {
+    err.chain()
+        .map(|cause| match cause.downcast_ref::<rust_tdlib::errors::Error>() {
+            Some(tdlib_err) => format!("{:?}", tdlib_err),
+            None => cause.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+fn classify(text: &str) -> Retry {
+    if let Some(seconds) = number_after(text, "FLOOD_WAIT_").or_else(|| number_after(text, "retry after ")) {
+        return Retry::After(Duration::from_secs(seconds));
+    }
+    match number_after(text, "code: ") {
+        Some(code) if TRANSIENT_CODES.contains(&code) => Retry::Backoff,
+        _ => Retry::Fail,
+    }
+}
+
+/// Parses the number following `prefix`, e.g. `30` of `FLOOD_WAIT_30`.
+fn number_after(text: &str, prefix: &str) -> Option<u64> {
+    let start = text.find(prefix)? + prefix.len();
+    let digits = text[start..].chars().take_while(|c| c.is_ascii_digit()).collect::<String>();
+    digits.parse().ok()
+}
+
+#[async_trait]
+impl TelegramApi for RateLimitedApi {
+    async fn get_me(&self) -> Result<User> {
+        self.call("get_me", true, || self.inner.get_me()).await
+    }
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat> {
+        self.call("get_chat", true, || self.inner.get_chat(chat_id)).await
+    }
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup> {
+        self.call("get_supergroup", true, || self.inner.get_supergroup(supergroup_id))
+            .await
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<User> {
+        self.call("get_user", true, || self.inner.get_user(user_id)).await
+    }
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message> {
+        self.call("get_message", true, || self.inner.get_message(chat_id, message_id))
+            .await
+    }
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String> {
+        self.call("get_message_link", true, || {
+            self.inner.get_message_link(chat_id, message_id)
+        })
+        .await
+    }
+
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>> {
+        self.call("get_chat_history", true, || {
+            self.inner.get_chat_history(chat_id, from_message_id, limit)
+        })
+        .await
+    }
+
+    async fn download_file(&self, file_id: i32) -> Result<String> {
+        self.call("download_file", true, || self.inner.download_file(file_id))
+            .await
+    }
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat> {
+        self.call("create_private_chat", true, || {
+            self.inner.create_private_chat(user_id)
+        })
+        .await
+    }
+
+    async fn send_text(&self, chat_id: i64, reply_to_message_id: i64, text: String) -> Result<()> {
+        self.call("send_text", false, || {
+            self.inner.send_text(chat_id, reply_to_message_id, text.clone())
+        })
+        .await
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.call("close", false, || self.inner.close()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use rust_tdlib::types::User;
+
+    use super::{classify, RateLimitedApi, Retry, MAX_ATTEMPTS};
+    use crate::telegram::fake::FakeTelegram;
+
+    #[test]
+    fn test_classify() {
+        let tests = vec![
+            (
+                "telegram:get_chat: TdlibError(Error { code: 420, message: \"FLOOD_WAIT_17\" })",
+                Retry::After(Duration::from_secs(17)),
+            ),
+            (
+                "Error { code: 429, message: \"Too Many Requests: retry after 5\" }",
+                Retry::After(Duration::from_secs(5)),
+            ),
+            ("Error { code: 429, message: \"Too Many Requests\" }", Retry::Backoff),
+            ("Error { code: 500, message: \"Request aborted\" }", Retry::Backoff),
+            ("Error { code: 400, message: \"Chat not found\" }", Retry::Fail),
+            // only codes count, not words in messages
+            ("Error { code: 400, message: \"Connection id is invalid\" }", Retry::Fail),
+            ("receive response: channel closed", Retry::Fail),
+        ];
+        for (text, expected) in tests {
+            assert_eq!(classify(text), expected, "{}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flood_waits_are_limited() {
+        let me = User::from_json(r#"{"@type":"user","id":1,"first_name":"Me"}"#).expect("cannot parse json");
+        let api = RateLimitedApi::new(Box::new(FakeTelegram::new(me)), "+79991234567", 0.0);
+        let attempts = &AtomicU32::new(0);
+        let result = api
+            .call("get_me", true, || async move {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(anyhow!("Error {{ code: 420, message: \"FLOOD_WAIT_0\" }}"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), MAX_ATTEMPTS);
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use rust_tdlib::types::{Chat, Message, Supergroup, Update, User};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::TelegramApi;
+
+/// A line of a fixture file: either an update received by the updates reader or an API call with its result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FixtureRecord {
+    Update {
+        client_id: i32,
+        update: Value,
+    },
+    Call {
+        client_id: i32,
+        method: String,
+        args: Vec<Value>,
+        #[serde(default)]
+        response: Option<Value>,
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+
+pub fn read_fixture<P: AsRef<path::Path>>(path: P) -> Result<Vec<FixtureRecord>> {
+    let file = File::open(path).context("open fixture")?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.context("read fixture")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).context(format!("parse fixture line {}", i + 1))?);
+    }
+    Ok(records)
+}
+
+/// Writes fixture records, shared by the updates reader and all recording clients.
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<path::Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .context("open fixture file")?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record_update(&self, client_id: i32, update: &Update) {
+        match serde_json::to_value(update) {
+            Ok(update) => self.write(&FixtureRecord::Update { client_id, update }),
+            Err(err) => log::warn!("cannot serialize update: {}", err),
+        }
+    }
+
+    fn write(&self, record: &FixtureRecord) {
+        let result = serde_json::to_string(record)
+            .context("serialize fixture record")
+            .and_then(|mut line| {
+                line.push('\n');
+                let mut file = self.file.lock().expect("fixture lock poisoned");
+                file.write_all(line.as_bytes()).context("write fixture record")
+            });
+        if let Err(err) = result {
+            log::warn!("cannot record: {:?}", err);
+        }
+    }
+}
+
+/// [`TelegramApi`] passing calls to another implementation and recording their results.
+#[derive(Debug)]
+pub struct RecordingApi {
+    inner: Box<dyn TelegramApi>,
+    client_id: i32,
+    recorder: std::sync::Arc<Recorder>,
+}
+
+impl RecordingApi {
+    pub fn new(inner: Box<dyn TelegramApi>, client_id: i32, recorder: std::sync::Arc<Recorder>) -> Self {
+        Self {
+            inner,
+            client_id,
+            recorder,
+        }
+    }
+
+    fn record<T: Serialize>(&self, method: &str, args: Vec<Value>, result: &Result<T>) {
+        let (response, error) = match result {
+            Ok(response) => (serde_json::to_value(response).ok(), None),
+            Err(err) => (None, Some(format!("{:#}", err))),
+        };
+        self.recorder.write(&FixtureRecord::Call {
+            client_id: self.client_id,
+            method: method.to_string(),
+            args,
+            response,
+            error,
+        });
+    }
+}
+
+#[async_trait]
+impl TelegramApi for RecordingApi {
+    async fn get_me(&self) -> Result<User> {
+        let result = self.inner.get_me().await;
+        self.record("get_me", vec![], &result);
+        result
+    }
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat> {
+        let result = self.inner.get_chat(chat_id).await;
+        self.record("get_chat", vec![json!(chat_id)], &result);
+        result
+    }
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup> {
+        let result = self.inner.get_supergroup(supergroup_id).await;
+        self.record("get_supergroup", vec![json!(supergroup_id)], &result);
+        result
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<User> {
+        let result = self.inner.get_user(user_id).await;
+        self.record("get_user", vec![json!(user_id)], &result);
+        result
+    }
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message> {
+        let result = self.inner.get_message(chat_id, message_id).await;
+        self.record("get_message", vec![json!(chat_id), json!(message_id)], &result);
+        result
+    }
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String> {
+        let result = self.inner.get_message_link(chat_id, message_id).await;
+        self.record("get_message_link", vec![json!(chat_id), json!(message_id)], &result);
+        result
+    }
+
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>> {
+        let result = self.inner.get_chat_history(chat_id, from_message_id, limit).await;
+        self.record(
+            "get_chat_history",
+            vec![json!(chat_id), json!(from_message_id), json!(limit)],
+            &result,
+        );
+        result
+    }
+
+    async fn download_file(&self, file_id: i32) -> Result<String> {
+        let result = self.inner.download_file(file_id).await;
+        self.record("download_file", vec![json!(file_id)], &result);
+        result
+    }
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat> {
+        let result = self.inner.create_private_chat(user_id).await;
+        self.record("create_private_chat", vec![json!(user_id)], &result);
+        result
+    }
+
+    async fn send_text(&self, chat_id: i64, reply_to_message_id: i64, text: String) -> Result<()> {
+        let result = self.inner.send_text(chat_id, reply_to_message_id, text.clone()).await;
+        self.record(
+            "send_text",
+            vec![json!(chat_id), json!(reply_to_message_id), json!(text)],
+            &result,
+        );
+        result
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RecordedResult {
+    response: Option<Value>,
+    error: Option<String>,
+}
+
+/// [`TelegramApi`] answering with results recorded for a single client, without network access.
+/// Repeated calls get recorded results in order, the last one is reused once they run out.
+#[derive(Debug)]
+pub struct ReplayApi {
+    calls: Mutex<HashMap<(String, String), VecDeque<RecordedResult>>>,
+    /// Downloaded files are not recorded, placeholders are created here instead
+    files_dir: path::PathBuf,
+}
+
+impl ReplayApi {
+    pub fn new(records: &[FixtureRecord], client_id: i32, files_dir: path::PathBuf) -> Self {
+        let mut calls: HashMap<(String, String), VecDeque<RecordedResult>> = HashMap::new();
+        for record in records {
+            if let FixtureRecord::Call {
+                client_id: id,
+                method,
+                args,
+                response,
+                error,
+            } = record
+            {
+                if *id != client_id {
+                    continue;
+                }
+                calls
+                    .entry((method.clone(), Value::from(args.clone()).to_string()))
+                    .or_default()
+                    .push_back(RecordedResult {
+                        response: response.clone(),
+                        error: error.clone(),
+                    });
+            }
+        }
+        Self {
+            calls: Mutex::new(calls),
+            files_dir,
+        }
+    }
+
+    fn take<T: DeserializeOwned>(&self, method: &str, args: Vec<Value>) -> Result<T> {
+        let key = (method.to_string(), Value::from(args).to_string());
+        let recorded = {
+            let mut calls = self.calls.lock().expect("replay lock poisoned");
+            let queue = calls
+                .get_mut(&key)
+                .ok_or_else(|| anyhow!("no recorded result for {}{}", key.0, key.1))?;
+            if queue.len() > 1 {
+                queue.pop_front()
+            } else {
+                queue.front().cloned()
+            }
+        };
+        match recorded {
+            Some(RecordedResult {
+                response: Some(response),
+                ..
+            }) => serde_json::from_value(response).context(format!("parse recorded {}", method)),
+            Some(RecordedResult {
+                error: Some(error), ..
+            }) => Err(anyhow!(error)),
+            _ => Err(anyhow!("empty recorded result for {}", method)),
+        }
+    }
+}
+
+#[async_trait]
+impl TelegramApi for ReplayApi {
+    async fn get_me(&self) -> Result<User> {
+        self.take("get_me", vec![])
+    }
+
+    async fn get_chat(&self, chat_id: i64) -> Result<Chat> {
+        self.take("get_chat", vec![json!(chat_id)])
+    }
+
+    async fn get_supergroup(&self, supergroup_id: i64) -> Result<Supergroup> {
+        self.take("get_supergroup", vec![json!(supergroup_id)])
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<User> {
+        self.take("get_user", vec![json!(user_id)])
+    }
+
+    async fn get_message(&self, chat_id: i64, message_id: i64) -> Result<Message> {
+        self.take("get_message", vec![json!(chat_id), json!(message_id)])
+    }
+
+    async fn get_message_link(&self, chat_id: i64, message_id: i64) -> Result<String> {
+        self.take("get_message_link", vec![json!(chat_id), json!(message_id)])
+    }
+
+    async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        from_message_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>> {
+        self.take(
+            "get_chat_history",
+            vec![json!(chat_id), json!(from_message_id), json!(limit)],
+        )
+    }
+
+    async fn download_file(&self, file_id: i32) -> Result<String> {
+        let recorded: String = self.take("download_file", vec![json!(file_id)])?;
+        let file_name = path::Path::new(&recorded)
+            .file_name()
+            .context("recorded file has no name")?;
+        fs::create_dir_all(&self.files_dir).context("create placeholders dir")?;
+        let placeholder = self.files_dir.join(file_name);
+        File::create(&placeholder).context("create placeholder file")?;
+        Ok(placeholder.to_string_lossy().to_string())
+    }
+
+    async fn create_private_chat(&self, user_id: i64) -> Result<Chat> {
+        self.take("create_private_chat", vec![json!(user_id)])
+    }
+
+    async fn send_text(&self, chat_id: i64, _reply_to_message_id: i64, text: String) -> Result<()> {
+        log::info!("replay: send to {}: {}", chat_id, text);
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use rust_tdlib::types::{Chat, Message, User};
+use tempfile::TempDir;
+
+use teleforward::account::{replay_fixture, sync, ClientWithMeta, SyncOptions};
+use teleforward::commands::{handle_control_command, ControlCommand};
+use teleforward::config::AccountSettings;
+use teleforward::dry_run::{ContentTotals, DryRunReport};
+use teleforward::index::SearchQuery;
+use teleforward::raw::RawStore;
+use teleforward::skip_list::SkipList;
+use teleforward::telegram::fake::FakeTelegram;
+
+fn text_message(chat_id: i64, id: i64, text: &str, entities: &str) -> Message {
+    dated_text_message(chat_id, id, 1700000000, text, entities)
+}
+
+fn dated_text_message(chat_id: i64, id: i64, date: i64, text: &str, entities: &str) -> Message {
+    Message::from_json(&format!(
+        r#"{{"@type":"message","id":{},"chat_id":{},"date":{},"content":{{"@type":"messageText","text":{{"@type":"formattedText","text":"{}","entities":[{}]}}}}}}"#,
+        id, chat_id, date, text, entities
+    ))
+    .expect("cannot parse json")
+}
+
+/// Fake telegram of user 1 with its Saved Messages chat.
+fn fake_saved_messages() -> FakeTelegram {
+    let fake = FakeTelegram::new(User::from_json(r#"{"@type":"user","id":1,"first_name":"Me"}"#).expect("cannot parse json"));
+    fake.add_chat(
+        Chat::from_json(r#"{"@type":"chat","id":1,"type":{"@type":"chatTypePrivate","user_id":1},"title":"Me"}"#)
+            .expect("cannot parse json"),
+    );
+    fake
+}
+
+/// Account with its files in a directory which is removed when the returned `TempDir` is dropped.
+fn test_account() -> (TempDir, AccountSettings) {
+    let dir = TempDir::new().expect("cannot create temp dir");
+    let account = AccountSettings {
+        phone: "+10000000000".to_string(),
+        tddb_dir: dir.path().join("tddb").to_string_lossy().to_string(),
+        file_path: dir.path().join("data.md").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    (dir, account)
+}
+
+#[tokio::test]
+async fn test_sync_with_fake_telegram() {
+    let fake = fake_saved_messages();
+    fake.add_message(text_message(1, 1, "first", ""));
+    fake.add_message(text_message(1, 2, "second", ""));
+    fake.add_message(text_message(
+        1,
+        3,
+        "/status",
+        r#"{"@type":"textEntity","offset":0,"length":7,"type":{"@type":"textEntityTypeBotCommand"}}"#,
+    ));
+
+    let (_dir, account) = test_account();
+    let client_meta = ClientWithMeta::new(&account, Box::new(fake), 1, 1, None).expect("cannot create client");
+    sync(&client_meta, &SyncOptions::default()).await.expect("sync failed");
+
+    let content = std::fs::read_to_string(&account.file_path).expect("cannot read output");
+    assert!(content.contains(r#"<a id="msg-1" data-type="text"></a>"#));
+    assert!(content.contains("**From:** SavedMessages"));
+    // history is returned newest first
+    let second = content.find("second").expect("second message is not archived");
+    let first = content.find("first").expect("first message is not archived");
+    assert!(second < first);
+    assert!(!content.contains("/status"));
+
+    let results = client_meta
+        .index
+        .search(&SearchQuery {
+            query: "second".to_string(),
+            limit: 10,
+            ..Default::default()
+        })
+        .expect("search failed");
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].message_id, 2);
+}
+
+#[tokio::test]
+async fn test_repeated_sync_archives_only_missing_messages() {
+    let fake = fake_saved_messages();
+    fake.add_message(text_message(1, 1, "first", ""));
+    fake.add_message(text_message(1, 2, "second", ""));
+    fake.add_message(text_message(1, 3, "third", ""));
+
+    let (_dir, account) = test_account();
+    // excluded with `/skip` before a restart
+    std::fs::write(SkipList::default_path(&account.file_path), "2\n").unwrap();
+    let client_meta = ClientWithMeta::new(&account, Box::new(fake), 1, 1, None).expect("cannot create client");
+    sync(&client_meta, &SyncOptions::default()).await.expect("sync failed");
+    sync(&client_meta, &SyncOptions::default()).await.expect("sync failed");
+
+    let content = std::fs::read_to_string(&account.file_path).expect("cannot read output");
+    assert_eq!(content.matches(r#"<a id="msg-1""#).count(), 1);
+    assert_eq!(content.matches(r#"<a id="msg-3""#).count(), 1);
+    assert!(!content.contains("second"));
+    let results = client_meta
+        .index
+        .search(&SearchQuery {
+            query: "first".to_string(),
+            limit: 10,
+            ..Default::default()
+        })
+        .expect("search failed");
+    assert_eq!(results.len(), 1);
+}
+
+#[tokio::test]
+async fn test_tag_rerenders_entry() {
+    let fake = fake_saved_messages();
+    fake.add_message(text_message(1, 1, "first", ""));
+
+    let (_dir, account) = test_account();
+    let client_meta = Arc::new(ClientWithMeta::new(&account, Box::new(fake), 1, 1, None).expect("cannot create client"));
+    sync(&client_meta, &SyncOptions::default()).await.expect("sync failed");
+    let untagged = std::fs::read_to_string(&account.file_path).expect("cannot read output");
+
+    let reply = Message::from_json(
+        r#"{"@type":"message","id":5,"chat_id":1,"date":1700000000,"content":{"@type":"messageText","text":{"@type":"formattedText","text":"/tag","entities":[]}},"reply_to":{"@type":"messageReplyToMessage","chat_id":1,"message_id":1}}"#,
+    )
+    .expect("cannot parse json");
+    for tags in [vec!["a".to_string()], vec!["b".to_string()]] {
+        handle_control_command(ControlCommand::Tag(tags), &reply, client_meta.clone())
+            .await
+            .expect("cannot tag");
+    }
+    let tagged = std::fs::read_to_string(&account.file_path).expect("cannot read output");
+    assert_eq!(tagged.replacen("**Tags:** #b\n\n", "", 1), untagged);
+
+    handle_control_command(ControlCommand::Tag(vec![]), &reply, client_meta.clone())
+        .await
+        .expect("cannot untag");
+    assert_eq!(std::fs::read_to_string(&account.file_path).expect("cannot read output"), untagged);
+}
+
+#[tokio::test]
+async fn test_sync_with_bounds() {
+    let fake = fake_saved_messages();
+    // 2023-11-01, 2023-11-02, ... 2023-11-05
+    for id in 1..=5 {
+        fake.add_message(dated_text_message(1, id, 1698796800 + (id - 1) * 86400, &format!("day {}", id), ""));
+    }
+
+    let (_dir, account) = test_account();
+    let client_meta = ClientWithMeta::new(&account, Box::new(fake), 1, 1, None).expect("cannot create client");
+    let date = |day| NaiveDate::from_ymd_opt(2023, 11, day).unwrap().and_hms_opt(0, 0, 0).unwrap();
+    let options = SyncOptions {
+        since: Some(date(2)),
+        until: Some(date(5)),
+        limit: Some(2),
+        ..Default::default()
+    };
+    sync(&client_meta, &options).await.expect("sync failed");
+
+    let content = std::fs::read_to_string(&account.file_path).expect("cannot read output");
+    assert!(!content.contains("day 5"));
+    assert!(content.contains("day 4"));
+    assert!(content.contains("day 3"));
+    assert!(!content.contains("day 2"));
+    assert!(!content.contains("day 1"));
+}
+
+#[tokio::test]
+async fn test_sync_dry_run() {
+    let fake = fake_saved_messages();
+    fake.add_message(text_message(1, 1, "first", ""));
+    fake.add_message(
+        Message::from_json(
+            r#"{"@type":"message","id":2,"chat_id":1,"date":1700000000,"content":{"@type":"messageDocument","document":{"@type":"document","file_name":"report.pdf","mime_type":"application/pdf","document":{"@type":"file","id":5,"size":2048,"expected_size":2048,"local":{"@type":"localFile"},"remote":{"@type":"remoteFile"}}},"caption":{"@type":"formattedText","text":"quarterly","entities":[]}}}"#,
+        )
+        .expect("cannot parse json"),
+    );
+
+    let (dir, mut account) = test_account();
+    account.store_raw = true;
+    let report_path = dir.path().join("report.md");
+    let report = Arc::new(DryRunReport::new(report_path.to_str()).expect("cannot create report"));
+    let client_meta =
+        ClientWithMeta::new(&account, Box::new(fake), 1, 1, Some(report.clone())).expect("cannot create client");
+    sync(&client_meta, &SyncOptions::default()).await.expect("sync failed");
+    client_meta.shutdown().await.expect("cannot shut down");
+    report.finish().expect("cannot finish report");
+
+    // nothing is written and the document is not downloaded, the fake would fail the download
+    assert!(!std::path::Path::new(&account.file_path).exists());
+    assert!(!account.index_path().exists());
+    assert!(!RawStore::default_path(&account.file_path).exists());
+    let report_content = std::fs::read_to_string(&report_path).expect("cannot read report");
+    assert!(report_content.contains("first"));
+    assert!(report_content.contains("report.pdf"));
+
+    let totals = report.totals();
+    assert_eq!(totals["text"].messages, 1);
+    assert_eq!(
+        totals["document"],
+        ContentTotals {
+            messages: 1,
+            attachments: 1,
+            attachments_size: 2048
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_sync_skips_unsupported_sources() {
+    let fake = fake_saved_messages();
+    fake.add_chat(
+        Chat::from_json(r#"{"@type":"chat","id":7,"type":{"@type":"chatTypeSecret","secret_chat_id":7,"user_id":2},"title":"Secret"}"#)
+            .expect("cannot parse json"),
+    );
+    fake.add_message(text_message(1, 1, "first", ""));
+    fake.add_message(
+        Message::from_json(
+            r#"{"@type":"message","id":2,"chat_id":1,"date":1700000000,"forward_info":{"@type":"messageForwardInfo","origin":{"@type":"messageOriginChat","sender_chat_id":7,"author_signature":""},"date":1700000000,"from_chat_id":0,"from_message_id":0},"content":{"@type":"messageText","text":{"@type":"formattedText","text":"from secret chat","entities":[]}}}"#,
+        )
+        .expect("cannot parse json"),
+    );
+    fake.add_message(text_message(1, 3, "third", ""));
+
+    let (_dir, account) = test_account();
+    let client_meta = ClientWithMeta::new(&account, Box::new(fake), 1, 1, None).expect("cannot create client");
+    sync(&client_meta, &SyncOptions::default()).await.expect("sync failed");
+
+    let content = std::fs::read_to_string(&account.file_path).expect("cannot read output");
+    assert!(content.contains("first"));
+    assert!(content.contains("third"));
+    assert!(!content.contains("from secret chat"));
+}
+
+#[tokio::test]
+async fn test_replay_text_and_reply() {
+    let dir = TempDir::new().expect("cannot create temp dir");
+    let outputs = replay_fixture(
+        std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/text_and_reply.jsonl")),
+        dir.path(),
+    )
+    .await
+    .expect("replay failed");
+    assert_eq!(outputs.len(), 1);
+
+    let content = std::fs::read_to_string(&outputs[0]).expect("cannot read output");
+    assert!(content.contains("hello **world**"));
+    assert!(content.contains("**In reply to:** [message 10](#msg-10)"));
+    assert!(content.contains("> hello world"));
+    // messages from other chats are not archived
+    assert!(!content.contains("not saved"));
+}